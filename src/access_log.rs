@@ -0,0 +1,284 @@
+use crate::capability::hex;
+use crate::http::header_value;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tiny_http::Request;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Logfmt,
+    Json,
+    Combined,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "logfmt" => Ok(Format::Logfmt),
+            "json" => Ok(Format::Json),
+            "combined" => Ok(Format::Combined),
+            _ => Err(format!("log-format must be logfmt, json or combined, got {value}")),
+        }
+    }
+}
+
+/// One line of the access log, filled in while the request is handled.
+pub struct Entry {
+    started: Instant,
+    ts: DateTime<Local>,
+    pub id: String,
+    remote: Option<SocketAddr>,
+    pub user: Option<String>,
+    method: String,
+    url: String,
+    version: String,
+    user_agent: Option<String>,
+    referer: Option<String>,
+    pub file: Option<PathBuf>,
+    pub status: u16,
+    pub bytes: u64,
+    pub received: Option<u64>,
+    pub complete: bool,
+    duration: Duration,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    ts: String,
+    id: &'a str,
+    ip: Option<String>,
+    user: Option<&'a str>,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    received: Option<u64>,
+    complete: bool,
+    duration_ms: f64,
+    file: Option<String>,
+    user_agent: Option<&'a str>,
+    referer: Option<&'a str>,
+}
+
+impl Entry {
+    pub fn new(request: &Request) -> Entry {
+        let mut id = [0u8; 8];
+        let _ = getrandom::fill(&mut id);
+        Entry {
+            started: Instant::now(),
+            ts: Local::now(),
+            id: hex(&id),
+            remote: request.remote_addr().copied(),
+            user: None,
+            method: request.method().to_string(),
+            url: request.url().to_string(),
+            version: request.http_version().to_string(),
+            user_agent: header_value(request, "User-Agent").map(str::to_string),
+            referer: header_value(request, "Referer").map(str::to_string),
+            file: None,
+            status: 0,
+            bytes: 0,
+            received: None,
+            complete: false,
+            duration: Duration::ZERO,
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.duration = self.started.elapsed();
+    }
+
+    pub fn write(&self, format: Format) {
+        match format {
+            Format::Logfmt => println!("{}", self.logfmt()),
+            Format::Json => println!("{}", self.json()),
+            Format::Combined => println!("{}", self.combined()),
+        }
+    }
+
+    /// The client address without its port, with IPv4-mapped IPv6 shown as IPv4.
+    fn ip(&self) -> Option<String> {
+        self.remote.map(|addr| addr.ip().to_canonical().to_string())
+    }
+
+    fn logfmt(&self) -> String {
+        let ip = self.ip().unwrap_or_else(|| "-".to_string());
+        let file = self
+            .file
+            .as_ref()
+            .map(|file| file.display().to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let received = self
+            .received
+            .map(|n| format!(" received={n}"))
+            .unwrap_or_default();
+        format!(
+            "ts={} id={} ip={} user={} method={} path={} status={} bytes={}{} complete={} duration_ms={:.1} file={} ua={} referer={}",
+            self.ts.format("%Y%m%d-%H:%M:%S%z"),
+            self.id,
+            ip,
+            logfmt_value(self.user.as_deref().unwrap_or("-")),
+            self.method,
+            logfmt_value(&self.url),
+            self.status,
+            self.bytes,
+            received,
+            self.complete,
+            self.duration.as_secs_f64() * 1000.0,
+            logfmt_value(&file),
+            logfmt_value(self.user_agent.as_deref().unwrap_or("-")),
+            logfmt_value(self.referer.as_deref().unwrap_or("-")),
+        )
+    }
+
+    fn json(&self) -> String {
+        let entry = JsonEntry {
+            ts: self.ts.to_rfc3339(),
+            id: &self.id,
+            ip: self.ip(),
+            user: self.user.as_deref(),
+            method: &self.method,
+            path: &self.url,
+            status: self.status,
+            bytes: self.bytes,
+            received: self.received,
+            complete: self.complete,
+            duration_ms: self.duration.as_secs_f64() * 1000.0,
+            file: self.file.as_ref().map(|file| file.display().to_string()),
+            user_agent: self.user_agent.as_deref(),
+            referer: self.referer.as_deref(),
+        };
+        serde_json::to_string(&entry).unwrap_or_default()
+    }
+
+    fn combined(&self) -> String {
+        let ip = self.ip().unwrap_or_else(|| "-".to_string());
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            n => n.to_string(),
+        };
+        format!(
+            "{} - {} [{}] \"{} {} HTTP/{}\" {} {} \"{}\" \"{}\"",
+            ip,
+            self.user.as_deref().unwrap_or("-"),
+            self.ts.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            quote_escape(&self.url),
+            self.version,
+            self.status,
+            bytes,
+            quote_escape(self.referer.as_deref().unwrap_or("-")),
+            quote_escape(self.user_agent.as_deref().unwrap_or("-")),
+        )
+    }
+}
+
+/// Writes a server event, such as a ban, next to the access log lines.
+/// Combined has no room for events, so they go to stderr there.
+pub fn event(format: Format, name: &str, fields: &[(&str, String)]) {
+    let ts = Local::now();
+    match format {
+        Format::Json => {
+            let mut object = serde_json::Map::new();
+            object.insert("ts".into(), ts.to_rfc3339().into());
+            object.insert("event".into(), name.into());
+            for (key, value) in fields {
+                object.insert(key.to_string(), value.clone().into());
+            }
+            println!("{}", serde_json::Value::Object(object));
+        }
+        Format::Logfmt | Format::Combined => {
+            let mut line = format!("ts={} event={}", ts.format("%Y%m%d-%H:%M:%S%z"), name);
+            for (key, value) in fields {
+                line.push_str(&format!(" {}={}", key, logfmt_value(value)));
+            }
+            if format == Format::Combined {
+                eprintln!("{line}");
+            } else {
+                println!("{line}");
+            }
+        }
+    }
+}
+
+fn logfmt_value(value: &str) -> String {
+    if !value.is_empty() && !value.contains(|c: char| c == ' ' || c == '"' || c == '=' || c.is_control()) {
+        return value.to_string();
+    }
+    format!("\"{}\"", quote_escape(value))
+}
+
+fn quote_escape(value: &str) -> String {
+    value.escape_default().to_string()
+}
+
+/// Counts body bytes the server actually got through: a chunk only counts
+/// once the next read shows the previous write went out.
+pub struct Counted<R> {
+    inner: R,
+    pending: u64,
+    written: Arc<AtomicU64>,
+}
+
+impl<R> Counted<R> {
+    pub fn new(inner: R) -> (Self, Arc<AtomicU64>) {
+        let written = Arc::new(AtomicU64::new(0));
+        let counted = Counted {
+            inner,
+            pending: 0,
+            written: Arc::clone(&written),
+        };
+        (counted, written)
+    }
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.written.fetch_add(self.pending, Ordering::Relaxed);
+        self.pending = 0;
+        let n = self.inner.read(buf)?;
+        self.pending = n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiny_http::TestRequest;
+
+    fn entry(remote: &str, path: &str) -> Entry {
+        let request = TestRequest::new()
+            .with_remote_addr(remote.parse().unwrap())
+            .with_path(path)
+            .into();
+        Entry::new(&request)
+    }
+
+    #[test]
+    fn every_format_logs_the_bare_canonical_ip() {
+        let entry = entry("[::ffff:192.0.2.7]:50123", "/a");
+        assert!(entry.logfmt().contains(" ip=192.0.2.7 "));
+        assert!(entry.json().contains("\"ip\":\"192.0.2.7\""));
+        assert!(entry.combined().starts_with("192.0.2.7 - "));
+    }
+
+    #[test]
+    fn quotes_in_the_target_stay_inside_their_field() {
+        let entry = entry("192.0.2.7:50123", "/a\"b c");
+        assert!(entry.combined().contains(" \"GET /a\\\"b c HTTP/1.1\" "));
+        assert!(entry.logfmt().contains(" path=\"/a\\\"b c\" "));
+    }
+}
@@ -0,0 +1,182 @@
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Option<Cidr> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr.trim_start_matches('[').trim_end_matches(']').parse().ok()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= max)?,
+            None => max,
+        };
+
+        // ::ffff:10.0.0.0/104 means the same thing as 10.0.0.0/8.
+        if let IpAddr::V6(v6) = addr
+            && let Some(v4) = v6.to_ipv4_mapped()
+            && prefix >= 96
+        {
+            return Some(Cidr {
+                addr: IpAddr::V4(v4),
+                prefix: prefix - 96,
+            });
+        }
+        Some(Cidr { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Client address filter; the most specific matching rule wins and deny
+/// wins ties. Without any allow rule, unmatched clients are let in.
+#[derive(Debug, Default)]
+pub struct Acl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl Acl {
+    pub fn allow(&mut self, cidr: Cidr) {
+        self.allow.push(cidr);
+    }
+
+    pub fn deny(&mut self, cidr: Cidr) {
+        self.deny.push(cidr);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn permits(&self, ip: Option<IpAddr>) -> bool {
+        let Some(ip) = ip.map(normalize) else {
+            return self.allow.is_empty();
+        };
+        let best = |rules: &[Cidr]| {
+            rules
+                .iter()
+                .filter(|rule| rule.contains(ip))
+                .map(|rule| i16::from(rule.prefix))
+                .max()
+                .unwrap_or(-1)
+        };
+        let (allow, deny) = (best(&self.allow), best(&self.deny));
+        if deny >= 0 && deny >= allow {
+            return false;
+        }
+        allow >= 0 || self.allow.is_empty()
+    }
+}
+
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> Option<IpAddr> {
+        Some(value.parse().unwrap())
+    }
+
+    fn rules(allow: &[&str], deny: &[&str]) -> Acl {
+        let mut acl = Acl::default();
+        for value in allow {
+            acl.allow(Cidr::parse(value).unwrap());
+        }
+        for value in deny {
+            acl.deny(Cidr::parse(value).unwrap());
+        }
+        acl
+    }
+
+    #[test]
+    fn parses_addresses_and_ranges() {
+        for value in ["10.0.0.1", "10.0.0.0/8", "::1", "[::1]", "2001:db8::/32", "0.0.0.0/0", "::/0"] {
+            assert!(Cidr::parse(value).is_some(), "{value}");
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_prefixes_and_garbage() {
+        for value in ["10.0.5.0/33", "::/129", "10.0.0.0/-1", "10.0.0.0/", "10.0.0/8", "*.log", "", "/8"] {
+            assert!(Cidr::parse(value).is_none(), "{value}");
+        }
+    }
+
+    #[test]
+    fn ipv4_mapped_clients_match_ipv4_rules() {
+        let acl = rules(&["10.0.0.0/8"], &[]);
+        assert!(acl.permits(ip("10.1.2.3")));
+        assert!(acl.permits(ip("::ffff:10.1.2.3")));
+        assert!(!acl.permits(ip("::ffff:192.168.1.1")));
+
+        // Rules written in the mapped form mean the same as plain IPv4 ones.
+        let acl = rules(&[], &["::ffff:10.0.5.0/120"]);
+        assert!(!acl.permits(ip("10.0.5.7")));
+        assert!(!acl.permits(ip("::ffff:10.0.5.7")));
+        assert!(acl.permits(ip("10.0.6.7")));
+    }
+
+    #[test]
+    fn zero_prefixes_cover_their_whole_family() {
+        let acl = rules(&[], &["0.0.0.0/0"]);
+        assert!(!acl.permits(ip("203.0.113.9")));
+        assert!(!acl.permits(ip("::ffff:203.0.113.9")));
+        assert!(acl.permits(ip("2001:db8::1")));
+
+        let acl = rules(&["::/0"], &[]);
+        assert!(acl.permits(ip("2001:db8::1")));
+        assert!(!acl.permits(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn the_most_specific_rule_wins() {
+        let acl = rules(&["10.0.0.0/8"], &["10.0.5.0/24"]);
+        assert!(acl.permits(ip("10.0.4.1")));
+        assert!(!acl.permits(ip("10.0.5.1")));
+        assert!(!acl.permits(ip("192.168.0.1")));
+
+        let acl = rules(&["10.0.5.7"], &["10.0.0.0/8"]);
+        assert!(acl.permits(ip("10.0.5.7")));
+        assert!(!acl.permits(ip("10.0.5.8")));
+
+        // Deny wins a tie.
+        let acl = rules(&["10.0.0.0/8"], &["10.0.0.0/8"]);
+        assert!(!acl.permits(ip("10.1.1.1")));
+    }
+
+    #[test]
+    fn clients_without_an_address_only_pass_without_allow_rules() {
+        assert!(rules(&[], &["10.0.0.0/8"]).permits(None));
+        assert!(!rules(&["10.0.0.0/8"], &[]).permits(None));
+        assert!(Acl::default().permits(ip("192.0.2.1")));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::capability::constant_time_eq;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Argon2, Params};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+pub const CHALLENGE: &str = "Basic realm=\"nsv\", charset=\"UTF-8\"";
+
+/// Users from an Apache htpasswd file holding bcrypt or argon2 hashes.
+pub struct Htpasswd {
+    users: HashMap<String, String>,
+    // Checked instead when the user does not exist, so that takes as long as
+    // a wrong password and does not tell which names are taken.
+    dummy: String,
+    // Digest of the last credentials that verified per user, so resumed
+    // downloads don't pay for a bcrypt/argon2 round on every request.
+    verified: Mutex<HashMap<String, [u8; 32]>>,
+}
+
+impl Htpasswd {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {e}", path.display()))?;
+        let mut users = HashMap::new();
+        let mut first = None;
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((user, hash)) = line.split_once(':') else {
+                return Err(format!("{}:{}: expected user:hash", path.display(), number + 1).into());
+            };
+            if !is_bcrypt(hash) && !hash.starts_with("$argon2") {
+                return Err(format!(
+                    "{}:{}: unsupported hash for {user}, use bcrypt (htpasswd -B) or argon2",
+                    path.display(),
+                    number + 1
+                )
+                .into());
+            }
+            first.get_or_insert_with(|| hash.to_string());
+            users.insert(user.to_string(), hash.to_string());
+        }
+        let Some(first) = first else {
+            return Err(format!("No users in {}", path.display()).into());
+        };
+        Ok(Htpasswd {
+            users,
+            dummy: dummy_hash(&first)
+                .map_err(|e| format!("{}: cannot hash like {first}: {e}", path.display()))?,
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the user name if the `Authorization` header holds valid credentials.
+    pub fn authenticate(&self, authorization: Option<&str>) -> Option<String> {
+        let (scheme, encoded) = authorization?.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = STANDARD.decode(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        let Some(hash) = self.users.get(user) else {
+            verify(password, &self.dummy);
+            return None;
+        };
+
+        let digest: [u8; 32] = Sha256::digest(decoded.as_bytes()).into();
+        if let Ok(verified) = self.verified.lock()
+            && verified.get(user).is_some_and(|known| constant_time_eq(known, &digest))
+        {
+            return Some(user.to_string());
+        }
+
+        if !verify(password, hash) {
+            return None;
+        }
+        if let Ok(mut verified) = self.verified.lock() {
+            verified.insert(user.to_string(), digest);
+        }
+        Some(user.to_string())
+    }
+}
+
+fn verify(password: &str, hash: &str) -> bool {
+    if is_bcrypt(hash) {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else {
+        PasswordHash::new(hash)
+            .map(|parsed| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &parsed)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// A hash of a random password with the same scheme and cost as `like`.
+fn dummy_hash(like: &str) -> Result<String, String> {
+    let mut password = [0u8; 16];
+    getrandom::fill(&mut password).map_err(|e| e.to_string())?;
+    if is_bcrypt(like) {
+        // `$2y$05$...`: the cost sits between the second and third `$`.
+        let cost = like
+            .get(4..6)
+            .and_then(|cost| cost.parse().ok())
+            .unwrap_or(bcrypt::DEFAULT_COST);
+        return bcrypt::hash(password, cost).map_err(|e| e.to_string());
+    }
+    let parsed = PasswordHash::new(like).map_err(|e| e.to_string())?;
+    let params = Params::try_from(&parsed).map_err(|e| e.to_string())?;
+    let salt = SaltString::encode_b64(&password).map_err(|e| e.to_string())?;
+    Argon2::new(Default::default(), Default::default(), params)
+        .hash_password(&password, &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn htpasswd(content: &str) -> Htpasswd {
+        let path = std::env::temp_dir().join(format!("nsv-htpasswd-{}", std::process::id()));
+        fs::write(&path, content).unwrap();
+        let htpasswd = Htpasswd::load(&path);
+        fs::remove_file(&path).unwrap();
+        htpasswd.unwrap()
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", STANDARD.encode(credentials))
+    }
+
+    #[test]
+    fn bcrypt_users_authenticate() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let htpasswd = htpasswd(&format!("alice:{hash}\n"));
+        assert!(htpasswd.dummy.starts_with("$2b$04$"));
+
+        assert_eq!(htpasswd.authenticate(Some(&basic("alice:secret"))), Some("alice".into()));
+        // Second time round from the cache.
+        assert_eq!(htpasswd.authenticate(Some(&basic("alice:secret"))), Some("alice".into()));
+        assert_eq!(htpasswd.authenticate(Some(&basic("alice:wrong"))), None);
+        assert_eq!(htpasswd.authenticate(Some(&basic("bob:secret"))), None);
+        assert_eq!(htpasswd.authenticate(Some("Bearer x")), None);
+        assert_eq!(htpasswd.authenticate(None), None);
+    }
+
+    #[test]
+    fn the_dummy_hash_matches_the_argon2_parameters() {
+        let params = Params::new(1024, 1, 1, None).unwrap();
+        let salt = SaltString::encode_b64(b"0123456789abcdef").unwrap();
+        let hash = Argon2::new(Default::default(), Default::default(), params)
+            .hash_password(b"secret", &salt)
+            .unwrap()
+            .to_string();
+        let htpasswd = htpasswd(&format!("alice:{hash}\n"));
+        assert!(htpasswd.dummy.contains("m=1024,t=1,p=1"), "{}", htpasswd.dummy);
+        assert_eq!(htpasswd.authenticate(Some(&basic("alice:secret"))), Some("alice".into()));
+        assert_eq!(htpasswd.authenticate(Some(&basic("bob:secret"))), None);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::decode_path;
+use crate::http::encode_path;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Secret first path segment that has to prefix every request.
+pub enum Capability {
+    PerRun(String),
+    PerFile([u8; 32]),
+}
+
+impl Capability {
+    pub fn per_run() -> io::Result<Self> {
+        let mut token = [0u8; 16];
+        getrandom::fill(&mut token)?;
+        Ok(Capability::PerRun(hex(&token)))
+    }
+
+    pub fn per_file() -> io::Result<Self> {
+        let mut secret = [0u8; 32];
+        getrandom::fill(&mut secret)?;
+        Ok(Capability::PerFile(secret))
+    }
+
+    /// Returns the rest of `path` (with its leading slash) if the token in
+    /// its first segment is valid for it.
+    pub fn strip<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let path = path.strip_prefix('/')?;
+        let (token, rest) = path.split_once('/')?;
+        let expected = match self {
+            Capability::PerRun(token) => token.clone(),
+            Capability::PerFile(secret) => file_token(secret, &decode_path(rest)),
+        };
+        constant_time_eq(token.as_bytes(), expected.as_bytes()).then(|| &path[token.len()..])
+    }
+
+    pub fn url_path(&self, rel: &str) -> String {
+        let token = match self {
+            Capability::PerRun(token) => token.clone(),
+            Capability::PerFile(secret) => file_token(secret, rel),
+        };
+        format!("/{token}{}", encode_path(rel))
+    }
+}
+
+fn file_token(secret: &[u8; 32], rel: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key size");
+    mac.update(rel.as_bytes());
+    hex(&mac.finalize().into_bytes()[..16])
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Relative paths of every regular file below `base_dir`, `/`-separated.
+pub fn list_files(base_dir: &Path) -> Vec<String> {
+    let mut files = Vec::new();
+    let mut pending = vec![base_dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            let path = entry.path();
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_file()
+                && let Ok(rel) = path.strip_prefix(base_dir)
+                && let Some(rel) = rel.to_str()
+            {
+                files.push(rel.replace(std::path::MAIN_SEPARATOR, "/"));
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_file_tokens_are_keyed_macs_of_the_path() {
+        let secret = [7u8; 32];
+        let mut mac = HmacSha256::new_from_slice(&secret).unwrap();
+        mac.update(b"docs/a b.pdf");
+        let expected = hex(&mac.finalize().into_bytes()[..16]);
+        assert_eq!(file_token(&secret, "docs/a b.pdf"), expected);
+
+        let capability = Capability::PerFile(secret);
+        let url = capability.url_path("docs/a b.pdf");
+        assert_eq!(url, format!("/{expected}/docs/a%20b.pdf"));
+        assert_eq!(capability.strip(&url), Some("/docs/a%20b.pdf"));
+        assert_eq!(capability.strip(&format!("/{expected}/docs/other.pdf")), None);
+        assert_eq!(Capability::PerFile([8u8; 32]).strip(&url), None);
+    }
+}
@@ -0,0 +1,292 @@
+use crate::capability::hex;
+use crate::http::{header, header_value, http_date, parse_http_date, unix_secs};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tiny_http::{Header, Request};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EtagMode {
+    #[default]
+    Metadata,
+    Hash,
+}
+
+impl FromStr for EtagMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "metadata" => Ok(EtagMode::Metadata),
+            "hash" => Ok(EtagMode::Hash),
+            _ => Err(format!("etag must be metadata or hash, got {value}")),
+        }
+    }
+}
+
+// Size and mtime the hash was computed for, and the resulting tag.
+type CachedHash = (u64, Option<SystemTime>, String);
+
+/// Hands out entity tags, remembering content hashes until the file changes.
+pub struct Etags {
+    mode: EtagMode,
+    hashes: Mutex<HashMap<PathBuf, CachedHash>>,
+}
+
+impl Etags {
+    pub fn new(mode: EtagMode) -> Self {
+        Etags {
+            mode,
+            hashes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `file` is what was opened for `path`; hashes are remembered by path.
+    pub fn validators(&self, path: &Path, file: &File, meta: &Metadata) -> io::Result<Validators> {
+        let modified = meta.modified().ok();
+        let etag = match self.mode {
+            EtagMode::Metadata => metadata_etag(meta),
+            EtagMode::Hash => self.hash_etag(path, file, meta.len(), modified)?,
+        };
+        Ok(Validators { etag, modified })
+    }
+
+    fn hash_etag(
+        &self,
+        path: &Path,
+        mut file: &File,
+        len: u64,
+        modified: Option<SystemTime>,
+    ) -> io::Result<String> {
+        if let Ok(hashes) = self.hashes.lock()
+            && let Some((cached_len, cached_modified, etag)) = hashes.get(path)
+            && *cached_len == len
+            && *cached_modified == modified
+        {
+            return Ok(etag.clone());
+        }
+
+        let mut hasher = Sha256::new();
+        file.seek(SeekFrom::Start(0))?;
+        io::copy(&mut file, &mut hasher)?;
+        let etag = format!("\"{}\"", hex(&hasher.finalize()[..16]));
+        if let Ok(mut hashes) = self.hashes.lock() {
+            hashes.insert(path.to_path_buf(), (len, modified, etag.clone()));
+        }
+        Ok(etag)
+    }
+}
+
+#[cfg(unix)]
+fn metadata_etag(meta: &Metadata) -> String {
+    use std::os::unix::fs::MetadataExt;
+    let nanos = (meta.mtime() as u64)
+        .wrapping_mul(1_000_000_000)
+        .wrapping_add(meta.mtime_nsec() as u64);
+    format!("\"{:x}-{:x}-{:x}\"", meta.ino(), meta.len(), nanos)
+}
+
+#[cfg(not(unix))]
+fn metadata_etag(meta: &Metadata) -> String {
+    let nanos = meta
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", meta.len(), nanos)
+}
+
+pub struct Validators {
+    pub etag: String,
+    pub modified: Option<SystemTime>,
+}
+
+impl Validators {
+    pub fn headers(&self) -> Vec<Header> {
+        let mut headers = vec![header("ETag", &self.etag)];
+        if let Some(modified) = self.modified {
+            headers.push(header("Last-Modified", &http_date(modified)));
+        }
+        headers
+    }
+}
+
+pub enum Outcome {
+    Proceed,
+    NotModified,
+    PreconditionFailed,
+}
+
+/// RFC 9110 section 13.2.2, for GET and HEAD.
+pub fn evaluate(request: &Request, validators: &Validators) -> Outcome {
+    if let Some(if_match) = header_value(request, "If-Match") {
+        if !matches_any(if_match, &validators.etag, false) {
+            return Outcome::PreconditionFailed;
+        }
+    } else if let Some(since) = header_value(request, "If-Unmodified-Since").and_then(parse_http_date)
+        && validators
+            .modified
+            .is_none_or(|modified| unix_secs(modified) > unix_secs(since))
+    {
+        return Outcome::PreconditionFailed;
+    }
+
+    if let Some(if_none_match) = header_value(request, "If-None-Match") {
+        if matches_any(if_none_match, &validators.etag, true) {
+            return Outcome::NotModified;
+        }
+    } else if let Some(since) = header_value(request, "If-Modified-Since").and_then(parse_http_date)
+        && validators
+            .modified
+            .is_some_and(|modified| unix_secs(modified) <= unix_secs(since))
+    {
+        return Outcome::NotModified;
+    }
+
+    Outcome::Proceed
+}
+
+fn matches_any(list: &str, etag: &str, weak: bool) -> bool {
+    if list.trim() == "*" {
+        return true;
+    }
+    list.split(',').map(str::trim).any(|candidate| {
+        match candidate.strip_prefix("W/") {
+            Some(candidate) => weak && candidate == etag,
+            None => candidate == etag,
+        }
+    })
+}
+
+/// Strong comparison, as If-Range requires.
+pub fn strong_match(candidate: &str, etag: &str) -> bool {
+    !candidate.starts_with("W/") && candidate == etag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::time::{Duration, UNIX_EPOCH};
+    use tiny_http::TestRequest;
+
+    fn validators() -> Validators {
+        Validators {
+            etag: "\"abc\"".to_string(),
+            modified: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+        }
+    }
+
+    fn date(offset: i64) -> String {
+        let secs = 1_700_000_000u64.checked_add_signed(offset).unwrap();
+        http_date(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    fn outcome(headers: &[(&str, &str)], validators: &Validators) -> &'static str {
+        let request = headers
+            .iter()
+            .fold(TestRequest::new(), |request, (name, value)| {
+                request.with_header(header(name, value))
+            });
+        match evaluate(&request.into(), validators) {
+            Outcome::Proceed => "proceed",
+            Outcome::NotModified => "304",
+            Outcome::PreconditionFailed => "412",
+        }
+    }
+
+    #[test]
+    fn etag_lists_compare_weakly_only_for_if_none_match() {
+        assert!(matches_any("\"abc\"", "\"abc\"", false));
+        assert!(matches_any("\"x\", \"abc\"", "\"abc\"", false));
+        assert!(matches_any(" * ", "\"abc\"", false));
+        assert!(!matches_any("W/\"abc\"", "\"abc\"", false));
+        assert!(matches_any("W/\"abc\"", "\"abc\"", true));
+        assert!(!matches_any("\"abcd\", W/\"ab\"", "\"abc\"", true));
+        assert!(strong_match("\"abc\"", "\"abc\""));
+        assert!(!strong_match("W/\"abc\"", "\"abc\""));
+    }
+
+    #[test]
+    fn if_match_and_if_unmodified_since() {
+        let v = validators();
+        assert_eq!(outcome(&[("If-Match", "\"abc\"")], &v), "proceed");
+        assert_eq!(outcome(&[("If-Match", "*")], &v), "proceed");
+        assert_eq!(outcome(&[("If-Match", "\"old\"")], &v), "412");
+        assert_eq!(outcome(&[("If-Match", "W/\"abc\"")], &v), "412");
+        assert_eq!(outcome(&[("If-Unmodified-Since", &date(0))], &v), "proceed");
+        assert_eq!(outcome(&[("If-Unmodified-Since", &date(-1))], &v), "412");
+        assert_eq!(outcome(&[("If-Unmodified-Since", "not a date")], &v), "proceed");
+    }
+
+    #[test]
+    fn if_match_takes_precedence_over_if_unmodified_since() {
+        let v = validators();
+        let headers = [("If-Match", "\"abc\""), ("If-Unmodified-Since", &date(-60) as &str)];
+        assert_eq!(outcome(&headers, &v), "proceed");
+        let headers = [("If-Match", "\"old\""), ("If-Unmodified-Since", &date(60) as &str)];
+        assert_eq!(outcome(&headers, &v), "412");
+    }
+
+    #[test]
+    fn if_none_match_and_if_modified_since() {
+        let v = validators();
+        assert_eq!(outcome(&[("If-None-Match", "\"abc\"")], &v), "304");
+        assert_eq!(outcome(&[("If-None-Match", "W/\"abc\"")], &v), "304");
+        assert_eq!(outcome(&[("If-None-Match", "*")], &v), "304");
+        assert_eq!(outcome(&[("If-None-Match", "\"old\"")], &v), "proceed");
+        assert_eq!(outcome(&[("If-Modified-Since", &date(0))], &v), "304");
+        assert_eq!(outcome(&[("If-Modified-Since", &date(60))], &v), "304");
+        assert_eq!(outcome(&[("If-Modified-Since", &date(-1))], &v), "proceed");
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_if_modified_since() {
+        let v = validators();
+        let headers = [("If-None-Match", "\"old\""), ("If-Modified-Since", &date(60) as &str)];
+        assert_eq!(outcome(&headers, &v), "proceed");
+        let headers = [("If-None-Match", "\"abc\""), ("If-Modified-Since", &date(-60) as &str)];
+        assert_eq!(outcome(&headers, &v), "304");
+    }
+
+    #[test]
+    fn failed_preconditions_win_over_not_modified() {
+        let v = validators();
+        let headers = [("If-Match", "\"old\""), ("If-None-Match", "\"abc\"")];
+        assert_eq!(outcome(&headers, &v), "412");
+    }
+
+    #[test]
+    fn dates_without_a_modification_time() {
+        let v = Validators {
+            etag: "\"abc\"".to_string(),
+            modified: None,
+        };
+        assert_eq!(outcome(&[("If-Unmodified-Since", &date(0))], &v), "412");
+        assert_eq!(outcome(&[("If-Modified-Since", &date(0))], &v), "proceed");
+    }
+
+    #[test]
+    fn hash_etag_covers_the_whole_file_wherever_the_offset_is() {
+        let path = std::env::temp_dir().join(format!("nsv-etag-{}", std::process::id()));
+        let content: Vec<u8> = (0..2048u32).map(|i| i as u8).collect();
+        File::create(&path).unwrap().write_all(&content).unwrap();
+
+        let mut file = File::open(&path).unwrap();
+        file.read_exact(&mut [0u8; 512]).unwrap();
+        let meta = file.metadata().unwrap();
+        let validators = Etags::new(EtagMode::Hash).validators(&path, &file, &meta).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let expected = format!("\"{}\"", hex(&Sha256::digest(&content)[..16]));
+        assert_eq!(validators.etag, expected);
+    }
+}
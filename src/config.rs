@@ -0,0 +1,482 @@
+use crate::access_log;
+use crate::conditional::EtagMode;
+use crate::encoding::Compression;
+use crate::resolve::Symlinks;
+use crate::units::{ByteRate, ByteSize, Interval};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+pub const DEFAULT_PORT: u16 = 8000;
+pub const DEFAULT_UPLOAD_MAX_SIZE: ByteSize = ByteSize(1 << 30);
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Interval = Interval(std::time::Duration::from_secs(30));
+pub const DEFAULT_WORKERS: usize = 64;
+pub const DEFAULT_QUEUE: usize = 256;
+pub const DEFAULT_BAN_TIME: Interval = Interval(std::time::Duration::from_secs(10 * 60));
+
+/// Every server option. Each layer (file, environment, command line) fills
+/// in its own `Config` and the layers are overlaid in that order.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub port: Option<u16>,
+    pub bind: Vec<String>,
+    pub force: Option<bool>,
+    pub tls: Option<bool>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub token: Option<bool>,
+    pub token_per_file: Option<bool>,
+    pub sign_key: Option<PathBuf>,
+    pub auth_file: Option<PathBuf>,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub deny_path: Vec<String>,
+    pub log_format: Option<access_log::Format>,
+    pub etag: Option<EtagMode>,
+    pub upload_dir: Option<PathBuf>,
+    pub upload_max_size: Option<ByteSize>,
+    pub inline: Vec<String>,
+    pub inline_scriptable: Option<bool>,
+    pub compress: Option<Compression>,
+    pub request_rate: Option<f64>,
+    pub request_burst: Option<f64>,
+    pub ban_threshold: Option<u32>,
+    pub ban_time: Option<Interval>,
+    pub rate_limit: Option<ByteRate>,
+    pub global_rate_limit: Option<ByteRate>,
+    pub workers: Option<usize>,
+    pub queue: Option<usize>,
+    pub max_requests_per_ip: Option<usize>,
+    pub shutdown_timeout: Option<Interval>,
+    pub once: Vec<PathBuf>,
+    pub max_downloads: Option<u32>,
+    pub share: Vec<PathBuf>,
+    pub allow_hidden: Option<bool>,
+    pub stealth: Option<bool>,
+    pub symlinks: Option<Symlinks>,
+    pub one_file_system: Option<bool>,
+}
+
+macro_rules! overlay {
+    ($base:ident, $top:ident; $($field:ident),* ; $($list:ident),*) => {
+        $(if $top.$field.is_some() { $base.$field = $top.$field; })*
+        $(if !$top.$list.is_empty() { $base.$list = $top.$list; })*
+    };
+}
+
+impl Config {
+    fn overlay(&mut self, top: Config) {
+        overlay!(self, top;
+            port, force, tls, tls_cert, tls_key, token, token_per_file, sign_key, auth_file,
+            log_format, etag, upload_dir, upload_max_size, inline_scriptable,
+            compress, request_rate, request_burst, ban_threshold, ban_time,
+            rate_limit, global_rate_limit, workers, queue, max_requests_per_ip,
+            shutdown_timeout, max_downloads, allow_hidden, stealth, symlinks,
+            one_file_system;
+            bind, allow, deny, deny_path, inline, once, share);
+    }
+
+    /// Applies the checks `set` makes to the merged result, since values
+    /// from the config file are deserialized without going through it.
+    fn validate(&self) -> Result<(), String> {
+        if let Some(port) = self.port {
+            check_port(port)?;
+        }
+        let counts = [
+            ("workers", self.workers),
+            ("queue", self.queue),
+            ("max-requests-per-ip", self.max_requests_per_ip),
+            ("max-downloads", self.max_downloads.map(|count| count as usize)),
+        ];
+        for (key, count) in counts {
+            if let Some(count) = count {
+                check_count(key, count)?;
+            }
+        }
+        for (key, number) in [("request-rate", self.request_rate), ("request-burst", self.request_burst)] {
+            if let Some(number) = number {
+                check_positive(key, number)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn with_defaults(mut self) -> Self {
+        self.port.get_or_insert(DEFAULT_PORT);
+        self.log_format.get_or_insert_default();
+        self.etag.get_or_insert_default();
+        self.symlinks.get_or_insert_default();
+        self.upload_max_size.get_or_insert(DEFAULT_UPLOAD_MAX_SIZE);
+        self.ban_time.get_or_insert(DEFAULT_BAN_TIME);
+        self.workers.get_or_insert(DEFAULT_WORKERS);
+        self.queue.get_or_insert(DEFAULT_QUEUE);
+        self.shutdown_timeout.get_or_insert(DEFAULT_SHUTDOWN_TIMEOUT);
+        for flag in [
+            &mut self.force,
+            &mut self.tls,
+            &mut self.token,
+            &mut self.token_per_file,
+            &mut self.inline_scriptable,
+            &mut self.allow_hidden,
+            &mut self.stealth,
+            &mut self.one_file_system,
+        ] {
+            flag.get_or_insert(false);
+        }
+        self
+    }
+
+    /// Sets one option from its textual form; list options accumulate.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "port" => self.port = Some(parse_port(value)?),
+            "bind" => self.bind.push(value.to_string()),
+            "force" => self.force = Some(parse_bool(key, value)?),
+            "tls" => self.tls = Some(parse_bool(key, value)?),
+            "tls-cert" => self.tls_cert = Some(value.into()),
+            "tls-key" => self.tls_key = Some(value.into()),
+            "token" => self.token = Some(parse_bool(key, value)?),
+            "token-per-file" => self.token_per_file = Some(parse_bool(key, value)?),
+            "sign-key" => self.sign_key = Some(value.into()),
+            "auth-file" => self.auth_file = Some(value.into()),
+            "allow" => self.allow.push(value.to_string()),
+            "deny" => self.deny.push(value.to_string()),
+            "deny-path" => self.deny_path.push(value.to_string()),
+            "log-format" => self.log_format = Some(value.parse()?),
+            "etag" => self.etag = Some(value.parse()?),
+            "upload-dir" => self.upload_dir = Some(value.into()),
+            "upload-max-size" => self.upload_max_size = Some(value.parse()?),
+            "inline" => self.inline.push(value.to_string()),
+            "inline-scriptable" => self.inline_scriptable = Some(parse_bool(key, value)?),
+            "compress" => self.compress = Some(value.parse()?),
+            "request-rate" => self.request_rate = Some(parse_positive(key, value)?),
+            "request-burst" => self.request_burst = Some(parse_positive(key, value)?),
+            "ban-threshold" => {
+                self.ban_threshold = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("{key} expects a whole number, got {value}"))?,
+                )
+            }
+            "ban-time" => self.ban_time = Some(value.parse()?),
+            "rate-limit" => self.rate_limit = Some(value.parse()?),
+            "global-rate-limit" => self.global_rate_limit = Some(value.parse()?),
+            "workers" => self.workers = Some(parse_count(key, value)?),
+            "queue" => self.queue = Some(parse_count(key, value)?),
+            "max-requests-per-ip" => self.max_requests_per_ip = Some(parse_count(key, value)?),
+            "shutdown-timeout" => self.shutdown_timeout = Some(value.parse()?),
+            "once" => self.once.push(value.into()),
+            "share" => self.share.push(value.into()),
+            "allow-hidden" => self.allow_hidden = Some(parse_bool(key, value)?),
+            "stealth" => self.stealth = Some(parse_bool(key, value)?),
+            "symlinks" => self.symlinks = Some(value.parse()?),
+            "one-file-system" => self.one_file_system = Some(parse_bool(key, value)?),
+            "max-downloads" => {
+                self.max_downloads = Some(
+                    u32::try_from(parse_count(key, value)?)
+                        .map_err(|_| format!("{key} is too large: {value}"))?,
+                )
+            }
+            _ => return Err(format!("Unknown option: {key}")),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Flag,
+    Value,
+    List,
+}
+
+const OPTIONS: &[(&str, Kind)] = &[
+    ("port", Kind::Value),
+    ("bind", Kind::List),
+    ("force", Kind::Flag),
+    ("tls", Kind::Flag),
+    ("tls-cert", Kind::Value),
+    ("tls-key", Kind::Value),
+    ("token", Kind::Flag),
+    ("token-per-file", Kind::Flag),
+    ("sign-key", Kind::Value),
+    ("auth-file", Kind::Value),
+    ("allow", Kind::List),
+    ("deny", Kind::List),
+    ("deny-path", Kind::List),
+    ("log-format", Kind::Value),
+    ("etag", Kind::Value),
+    ("upload-dir", Kind::Value),
+    ("upload-max-size", Kind::Value),
+    ("inline", Kind::List),
+    ("inline-scriptable", Kind::Flag),
+    ("compress", Kind::Value),
+    ("request-rate", Kind::Value),
+    ("request-burst", Kind::Value),
+    ("ban-threshold", Kind::Value),
+    ("ban-time", Kind::Value),
+    ("rate-limit", Kind::Value),
+    ("global-rate-limit", Kind::Value),
+    ("workers", Kind::Value),
+    ("queue", Kind::Value),
+    ("max-requests-per-ip", Kind::Value),
+    ("shutdown-timeout", Kind::Value),
+    ("once", Kind::List),
+    ("max-downloads", Kind::Value),
+    ("share", Kind::List),
+    ("allow-hidden", Kind::Flag),
+    ("stealth", Kind::Flag),
+    ("symlinks", Kind::Value),
+    ("one-file-system", Kind::Flag),
+];
+
+fn kind(key: &str) -> Option<Kind> {
+    OPTIONS
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, kind)| *kind)
+}
+
+fn parse_port(value: &str) -> Result<u16, String> {
+    let port = value
+        .parse::<u16>()
+        .map_err(|_| "Port must be a number between 1 and 65535".to_string())?;
+    check_port(port)
+}
+
+fn check_port(port: u16) -> Result<u16, String> {
+    match port {
+        0 => Err("Port must be between 1 and 65535".to_string()),
+        port => Ok(port),
+    }
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("{key} expects true or false, got {value}")),
+    }
+}
+
+fn parse_count(key: &str, value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(count) => check_count(key, count),
+        Err(_) => Err(format!("{key} expects a whole number of at least 1, got {value}")),
+    }
+}
+
+fn check_count(key: &str, count: usize) -> Result<usize, String> {
+    match count {
+        0 => Err(format!("{key} expects a whole number of at least 1, got 0")),
+        count => Ok(count),
+    }
+}
+
+fn parse_positive(key: &str, value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(number) => check_positive(key, number),
+        Err(_) => Err(format!("{key} expects a positive number, got {value}")),
+    }
+}
+
+fn check_positive(key: &str, number: f64) -> Result<f64, String> {
+    if number > 0.0 && number.is_finite() {
+        Ok(number)
+    } else {
+        Err(format!("{key} expects a positive number, got {number}"))
+    }
+}
+
+pub struct Loaded {
+    pub config: Config,
+    pub print_config: bool,
+}
+
+/// Merges the config file, `NSV_*` variables and `args`, later ones winning.
+/// `args` may start with `share`, in which case the positional arguments
+/// are the files to share rather than the port.
+pub fn load(args: impl Iterator<Item = String>) -> Result<Loaded, Box<dyn Error + Send + Sync>> {
+    let mut args = args.peekable();
+    let share = args.next_if(|arg| arg == "share").is_some();
+    let mut cli = Config::default();
+    let mut config_path: Option<PathBuf> = env::var_os("NSV_CONFIG").map(PathBuf::from);
+    let mut print_config = false;
+
+    while let Some(arg) = args.next() {
+        if arg == "--print-config" {
+            print_config = true;
+            continue;
+        }
+        if arg == "--config" {
+            config_path = Some(crate::flag_value(&mut args, &arg)?.into());
+            continue;
+        }
+        if arg.starts_with('-') {
+            set_flag(&mut cli, &arg, &mut args)?;
+            continue;
+        }
+
+        if share {
+            cli.set("share", &arg)?;
+            continue;
+        }
+        if cli.port.is_none() {
+            cli.set("port", &arg)?;
+            continue;
+        }
+        return Err("Too many positional arguments".into());
+    }
+    if share && cli.share.is_empty() {
+        return Err("Usage: nsv share <file>... [options]".into());
+    }
+
+    let mut config = read_file(config_path)?;
+    config.overlay(from_env()?);
+    config.overlay(cli);
+    config.validate()?;
+    Ok(Loaded {
+        config: config.with_defaults(),
+        print_config,
+    })
+}
+
+/// Applies one `--key value`, `--key=value`, `--flag` or `--no-flag`
+/// argument. The last two forms let the command line switch a flag either
+/// way, whatever the file or environment said.
+fn set_flag(
+    config: &mut Config,
+    arg: &str,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let name = arg.strip_prefix("--").unwrap_or_default();
+    let (key, inline) = match name.split_once('=') {
+        Some((key, value)) => (key, Some(value)),
+        None => (name, None),
+    };
+    match (kind(key), inline) {
+        (Some(Kind::Flag), None) => config.set(key, "true")?,
+        (Some(_), Some(value)) => config.set(key, value)?,
+        (Some(_), None) => config.set(key, &crate::flag_value(args, arg)?)?,
+        (None, inline) => match key.strip_prefix("no-") {
+            Some(flag) if inline.is_none() && kind(flag) == Some(Kind::Flag) => {
+                config.set(flag, "false")?
+            }
+            _ => return Err(format!("Unknown flag: {arg}").into()),
+        },
+    }
+    Ok(())
+}
+
+fn read_file(explicit: Option<PathBuf>) -> Result<Config, Box<dyn Error + Send + Sync>> {
+    let path = match explicit {
+        Some(path) => path,
+        None => match default_paths().into_iter().find(|path| path.is_file()) {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        },
+    };
+    let content =
+        fs::read_to_string(&path).map_err(|e| format!("Cannot read {}: {e}", path.display()))?;
+    toml::from_str(&content).map_err(|e| format!("Invalid config {}: {e}", path.display()).into())
+}
+
+fn default_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(".nsv.toml")];
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| crate::home_dir().map(|home| home.join(".config")));
+    if let Some(config_home) = config_home {
+        paths.push(config_home.join("nsv").join("config.toml"));
+    }
+    paths
+}
+
+fn from_env() -> Result<Config, String> {
+    let mut config = Config::default();
+    for (name, value) in env::vars() {
+        let Some(key) = name.strip_prefix("NSV_") else {
+            continue;
+        };
+        if key == "CONFIG" {
+            continue;
+        }
+        let key = key.to_ascii_lowercase().replace('_', "-");
+        let result = if kind(&key) == Some(Kind::List) {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .try_for_each(|item| config.set(&key, item))
+        } else {
+            config.set(&key, &value)
+        };
+        result.map_err(|e| format!("{name}: {e}"))?;
+    }
+    Ok(config)
+}
+
+pub fn to_toml(config: &Config) -> String {
+    toml::to_string(config).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_values_get_the_same_checks_as_flags() {
+        for content in [
+            "port = 0",
+            "workers = 0",
+            "queue = 0",
+            "max-requests-per-ip = 0",
+            "max-downloads = 0",
+            "request-rate = 0.0",
+            "request-rate = -2.5",
+            "request-burst = 0.0",
+        ] {
+            let config: Config = toml::from_str(content).unwrap();
+            assert!(config.validate().is_err(), "{content}");
+        }
+        let config: Config = toml::from_str("port = 8080\nworkers = 4\nrequest-rate = 0.5").unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    fn flags(args: &[&str]) -> Result<Config, Box<dyn Error + Send + Sync>> {
+        let mut config = Config::default();
+        let mut args = args.iter().map(|arg| arg.to_string());
+        while let Some(arg) = args.next() {
+            set_flag(&mut config, &arg, &mut args)?;
+        }
+        Ok(config)
+    }
+
+    #[test]
+    fn flags_can_be_switched_off_on_the_command_line() {
+        let config = flags(&["--tls", "--no-force", "--stealth=false", "--token=yes"]).unwrap();
+        assert_eq!(config.tls, Some(true));
+        assert_eq!(config.force, Some(false));
+        assert_eq!(config.stealth, Some(false));
+        assert_eq!(config.token, Some(true));
+
+        let mut file: Config = toml::from_str("tls = true\nstealth = true").unwrap();
+        file.overlay(flags(&["--no-tls", "--stealth=off"]).unwrap());
+        assert_eq!((file.tls, file.stealth), (Some(false), Some(false)));
+    }
+
+    #[test]
+    fn values_take_the_next_argument_or_an_inline_one() {
+        let config = flags(&["--port", "8080", "--bind=127.0.0.1", "--bind", "::1"]).unwrap();
+        assert_eq!(config.port, Some(8080));
+        assert_eq!(config.bind, ["127.0.0.1", "::1"]);
+        assert!(flags(&["--port"]).is_err());
+        assert!(flags(&["--no-port"]).is_err());
+        assert!(flags(&["--no-tls=true"]).is_err());
+        assert!(flags(&["--tls=maybe"]).is_err());
+        assert!(flags(&["--frobnicate"]).is_err());
+    }
+}
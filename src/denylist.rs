@@ -0,0 +1,150 @@
+use crate::glob::Glob;
+use std::path::Path;
+
+// Anything with a path component starting with a dot.
+const HIDDEN: &[&str] = &["**/.*", "**/.*/**"];
+
+// Version control metadata, credentials and key material. These stay
+// denied even with --allow-hidden.
+const SENSITIVE: &[&str] = &[
+    "**/.git",
+    "**/.git/**",
+    "**/.hg/**",
+    "**/.svn/**",
+    "**/.bzr/**",
+    "**/_darcs/**",
+    "**/CVS/**",
+    "**/.ssh/**",
+    "**/.gnupg/**",
+    "**/.aws/**",
+    "**/.kube/**",
+    "**/.docker/config.json",
+    ".env",
+    ".env.*",
+    ".netrc",
+    ".pgpass",
+    ".git-credentials",
+    ".npmrc",
+    ".pypirc",
+    ".htpasswd",
+    "*.htpasswd",
+    "id_rsa*",
+    "id_dsa*",
+    "id_ecdsa*",
+    "id_ed25519*",
+    "*.key",
+    "*key.pem",
+    "*.p12",
+    "*.pfx",
+    "*.jks",
+    "*.keystore",
+    "*.kdbx",
+];
+
+/// Paths that are never served, answered like missing files.
+pub struct Denylist {
+    globs: Vec<Glob>,
+    files: Vec<String>,
+}
+
+impl Denylist {
+    pub fn new(allow_hidden: bool, extra: Vec<Glob>) -> Self {
+        let hidden = HIDDEN.iter().filter(|_| !allow_hidden);
+        let globs = hidden
+            .chain(SENSITIVE)
+            .map(|pattern| Glob::new(pattern))
+            .chain(extra)
+            .collect();
+        Denylist {
+            globs,
+            files: Vec::new(),
+        }
+    }
+
+    /// Also denies `path` itself when it lives below `base_dir`, for the
+    /// password, key and certificate files nsv reads.
+    pub fn protect(&mut self, base_dir: &Path, path: &Path) {
+        if let Ok(path) = path.canonicalize()
+            && let Ok(rel) = path.strip_prefix(base_dir)
+        {
+            let rel = rel.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/");
+            self.files.push(rel);
+        }
+    }
+
+    /// `rel` is `/`-separated and relative to the served directory. `.` and
+    /// `..` segments are resolved first so they do not count as dotfiles.
+    pub fn denies(&self, rel: &str) -> bool {
+        let mut segments = Vec::new();
+        for segment in rel.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => {
+                    segments.pop();
+                }
+                segment => segments.push(segment),
+            }
+        }
+        let rel = segments.join("/");
+        self.files.contains(&rel) || self.globs.iter().any(|glob| glob.matches(&rel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sensitive_files_are_denied_at_any_depth() {
+        let denylist = Denylist::new(false, Vec::new());
+        for rel in ["users.htpasswd", "conf/site.htpasswd", ".htpasswd", "a/b/.htpasswd"] {
+            assert!(denylist.denies(rel), "{rel}");
+        }
+        assert!(!denylist.denies("htpasswd.txt"));
+    }
+
+    #[test]
+    fn credentials_stay_denied_with_allow_hidden() {
+        let denylist = Denylist::new(true, Vec::new());
+        for rel in [
+            ".git-credentials",
+            "home/.npmrc",
+            "home/.pypirc",
+            "site/.htpasswd",
+            "site/users.htpasswd",
+            ".env",
+            ".git/config",
+        ] {
+            assert!(denylist.denies(rel), "{rel}");
+        }
+        assert!(!denylist.denies(".well-known/security.txt"));
+    }
+
+    #[test]
+    fn extra_globs_extend_the_built_in_set() {
+        let denylist = Denylist::new(false, vec![Glob::new("*.log"), Glob::new("drafts/**")]);
+        assert!(denylist.denies("var/app.log"));
+        assert!(denylist.denies("drafts/a/b.txt"));
+        assert!(!denylist.denies("notes/drafts.txt"));
+        assert!(denylist.denies(".env"));
+    }
+
+    #[test]
+    fn protected_files_are_denied_by_exact_path() {
+        let base_dir = std::env::temp_dir()
+            .join(format!("nsv-deny-{}", std::process::id()));
+        std::fs::create_dir_all(base_dir.join("conf")).unwrap();
+        let base_dir = base_dir.canonicalize().unwrap();
+        std::fs::write(base_dir.join("conf/secret.bin"), "k").unwrap();
+
+        let mut denylist = Denylist::new(false, Vec::new());
+        denylist.protect(&base_dir, &base_dir.join("conf/secret.bin"));
+        denylist.protect(&base_dir, Path::new("/nonexistent/secret.bin"));
+        std::fs::remove_dir_all(&base_dir).unwrap();
+
+        assert!(denylist.denies("conf/secret.bin"));
+        assert!(denylist.denies("conf/./x/../secret.bin"));
+        assert!(!denylist.denies("secret.bin"));
+        assert!(!denylist.denies("other/secret.bin"));
+    }
+}
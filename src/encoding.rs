@@ -0,0 +1,163 @@
+use crate::resolve::Root;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, Metadata};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Not worth the framing overhead below this.
+const MIN_COMPRESS_LEN: u64 = 1024;
+
+/// Codecs `--compress` can apply on the fly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn token(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn encoder(self, body: Box<dyn Read + Send>) -> io::Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            Compression::Gzip => Box::new(flate2::read::GzEncoder::new(
+                body,
+                flate2::Compression::default(),
+            )),
+            Compression::Zstd => Box::new(zstd::stream::read::Encoder::new(body, 3)?),
+        })
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("compress must be gzip or zstd, got {value}")),
+        }
+    }
+}
+
+// Precompressed siblings, best ratio first.
+const SIBLINGS: &[(&str, &str)] = &[("br", "br"), ("zstd", "zst"), ("gzip", "gz")];
+
+/// What goes over the wire for one request.
+pub enum Representation {
+    Identity,
+    /// A `file.gz`-style sibling, served as is. Ranges apply to its bytes.
+    Precompressed {
+        file: File,
+        path: PathBuf,
+        coding: &'static str,
+    },
+    /// Compressed while streaming; the length is unknown so ranges are not offered.
+    OnTheFly(Compression),
+}
+
+pub fn negotiate(
+    accept_encoding: Option<&str>,
+    root: &Root,
+    path: &Path,
+    meta: &Metadata,
+    content_type: &str,
+    compress: Option<Compression>,
+    range_requested: bool,
+) -> Representation {
+    let Some(accept_encoding) = accept_encoding else {
+        return Representation::Identity;
+    };
+
+    for (coding, ext) in SIBLINGS {
+        if !accepts(accept_encoding, coding) {
+            continue;
+        }
+        if let Some((file, path)) = sibling(root, path, meta, ext) {
+            return Representation::Precompressed { file, path, coding };
+        }
+    }
+
+    match compress {
+        Some(compression)
+            if !range_requested
+                && meta.len() >= MIN_COMPRESS_LEN
+                && is_compressible(content_type)
+                && accepts(accept_encoding, compression.token()) =>
+        {
+            Representation::OnTheFly(compression)
+        }
+        _ => Representation::Identity,
+    }
+}
+
+/// A sibling only counts if it stays inside the served tree and is not older
+/// than the file it stands in for.
+fn sibling(root: &Root, path: &Path, original: &Metadata, ext: &str) -> Option<(File, PathBuf)> {
+    let rel = root.relative(path)?;
+    let sibling = root.open(&format!("{rel}.{ext}")).ok()?;
+    let meta = sibling.file.metadata().ok()?;
+    if !meta.is_file() {
+        return None;
+    }
+    if meta.modified().ok()? < original.modified().ok()? {
+        return None;
+    }
+    Some((sibling.file, sibling.path))
+}
+
+/// Whether `coding` has a non-zero weight in an `Accept-Encoding` value,
+/// explicitly or through `*`.
+pub fn accepts(accept_encoding: &str, coding: &str) -> bool {
+    let mut wildcard = None;
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(coding) || (coding == "gzip" && name.eq_ignore_ascii_case("x-gzip")) {
+            return q > 0.0;
+        }
+        if name == "*" {
+            wildcard = Some(q > 0.0);
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or(content_type).trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || matches!(
+            essence,
+            "application/json"
+                | "application/xml"
+                | "application/javascript"
+                | "application/toml"
+                | "application/yaml"
+                | "application/wasm"
+                | "application/x-tar"
+                | "font/ttf"
+                | "font/otf"
+        )
+}
+
+/// Gives an on-the-fly representation its own entity tag, since its bytes
+/// differ from the file on disk.
+pub fn tag_etag(etag: &str, coding: &str) -> String {
+    match etag.strip_suffix('"') {
+        Some(open) => format!("{open}-{coding}\""),
+        None => format!("{etag}-{coding}"),
+    }
+}
@@ -0,0 +1,183 @@
+/// A shell-style pattern over `/`-separated relative paths.
+///
+/// `*` and `?` stay within one path segment, `**` spans any number of
+/// segments and `[a-z]` / `[!a-z]` match one character from a class. A
+/// pattern without a `/` is matched against the file name alone, so `*.pdf`
+/// covers PDFs at any depth.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    pattern: Vec<char>,
+    basename_only: bool,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Glob {
+        let pattern = pattern.trim_start_matches('/');
+        Glob {
+            pattern: pattern.chars().collect(),
+            basename_only: !pattern.contains('/'),
+        }
+    }
+
+    pub fn matches(&self, rel: &str) -> bool {
+        let rel = rel.trim_start_matches('/');
+        let subject = if self.basename_only {
+            rel.rsplit('/').next().unwrap_or(rel)
+        } else {
+            rel
+        };
+        let subject: Vec<char> = subject.chars().collect();
+        match_here(&self.pattern, &subject)
+    }
+}
+
+fn match_here(pattern: &[char], subject: &[char]) -> bool {
+    let Some((&first, rest)) = pattern.split_first() else {
+        return subject.is_empty();
+    };
+    match first {
+        '*' if rest.first() == Some(&'*') => {
+            let mut rest = &rest[1..];
+            // `**/` also matches zero directories.
+            if rest.first() == Some(&'/') {
+                if match_here(&rest[1..], subject) {
+                    return true;
+                }
+                rest = &rest[1..];
+                return (0..subject.len())
+                    .filter(|&i| subject[i] == '/')
+                    .any(|i| match_here(rest, &subject[i + 1..]));
+            }
+            (0..=subject.len()).any(|i| match_here(rest, &subject[i..]))
+        }
+        '*' => {
+            for i in 0..=subject.len() {
+                if match_here(rest, &subject[i..]) {
+                    return true;
+                }
+                if subject.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        '?' => match subject.split_first() {
+            Some((&c, subject)) if c != '/' => match_here(rest, subject),
+            _ => false,
+        },
+        '[' => match (subject.split_first(), class(rest)) {
+            (Some((&c, subject)), Some((matches, rest))) if c != '/' => {
+                matches(c) && match_here(rest, subject)
+            }
+            (_, Some(_)) => false,
+            // An unterminated `[` is just a literal.
+            (_, None) => subject.first() == Some(&'[') && match_here(rest, &subject[1..]),
+        },
+        '\\' if !rest.is_empty() => {
+            subject.first() == Some(&rest[0]) && match_here(&rest[1..], &subject[1..])
+        }
+        c => subject.first() == Some(&c) && match_here(rest, &subject[1..]),
+    }
+}
+
+/// Parses the body of a `[...]` class, returning a predicate and the pattern
+/// after the closing bracket.
+fn class(pattern: &[char]) -> Option<(impl Fn(char) -> bool + '_, &[char])> {
+    let (negated, body) = match pattern.first() {
+        Some('!' | '^') => (true, &pattern[1..]),
+        _ => (false, pattern),
+    };
+    // A `]` right after the opening bracket is a member, not the end.
+    let close = body.iter().skip(1).position(|&c| c == ']')? + 1;
+    let members = &body[..close];
+    let matches = move |c: char| {
+        let mut found = false;
+        let mut i = 0;
+        while i < members.len() {
+            if i + 2 < members.len() && members[i + 1] == '-' {
+                found |= members[i] <= c && c <= members[i + 2];
+                i += 3;
+            } else {
+                found |= members[i] == c;
+                i += 1;
+            }
+        }
+        found != negated
+    };
+    Some((matches, &body[close + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, rel: &str) -> bool {
+        Glob::new(pattern).matches(rel)
+    }
+
+    #[test]
+    fn star_stays_within_a_segment() {
+        assert!(matches("docs/*.pdf", "docs/a.pdf"));
+        assert!(!matches("docs/*.pdf", "docs/sub/a.pdf"));
+        assert!(matches("docs/*", "docs/"));
+        assert!(!matches("a*b", "a/b"));
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("a?b", "a/b"));
+    }
+
+    #[test]
+    fn patterns_without_a_slash_match_the_file_name_at_any_depth() {
+        assert!(matches("*.pdf", "a.pdf"));
+        assert!(matches("*.pdf", "x/y/a.pdf"));
+        assert!(!matches("*.pdf", "a.pdf/readme"));
+        assert!(matches(".env", "app/.env"));
+        // With a slash the whole path has to match.
+        assert!(!matches("docs/*.pdf", "x/docs/a.pdf"));
+        assert!(matches("/docs/*.pdf", "/docs/a.pdf"));
+    }
+
+    #[test]
+    fn double_star_spans_segments() {
+        assert!(matches("drafts/**", "drafts/a"));
+        assert!(matches("drafts/**", "drafts/a/b/c"));
+        assert!(!matches("drafts/**", "other/drafts/a"));
+        assert!(matches("**/.git/**", ".git/config"));
+        assert!(matches("**/.git/**", "a/b/.git/objects/x"));
+        assert!(!matches("**/.git/**", "a/.github/x"));
+        assert!(matches("**/.git", ".git"));
+        assert!(matches("**/.git", "a/.git"));
+        assert!(!matches("**/.git", "a.git"));
+        assert!(matches("a/**/b.txt", "a/b.txt"));
+        assert!(matches("a/**/b.txt", "a/x/y/b.txt"));
+        assert!(!matches("a/**/b.txt", "a/xb.txt"));
+        assert!(matches("**.log", "a/b.log"));
+    }
+
+    #[test]
+    fn classes_match_one_character() {
+        assert!(matches("[a-c].txt", "b.txt"));
+        assert!(!matches("[a-c].txt", "d.txt"));
+        assert!(matches("[!a-c].txt", "d.txt"));
+        assert!(!matches("[!a-c].txt", "a.txt"));
+        assert!(matches("[^a-c].txt", "d.txt"));
+        assert!(matches("[xyz]", "y"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[!]]", "a"));
+        assert!(matches("id_[rd]sa*", "home/id_rsa.pub"));
+        assert!(!matches("a[!x]b", "a/b"));
+        // An unterminated class is a literal `[`.
+        assert!(matches("[abc", "[abc"));
+        assert!(!matches("[abc", "a"));
+    }
+
+    #[test]
+    fn backslash_escapes_the_next_character() {
+        assert!(matches(r"\*.txt", "*.txt"));
+        assert!(!matches(r"\*.txt", "a.txt"));
+        assert!(matches(r"what\?", "what?"));
+        assert!(!matches(r"what\?", "whats"));
+        assert!(matches(r"\[a]", "[a]"));
+        // A trailing backslash matches itself.
+        assert!(matches("a\\", "a\\"));
+    }
+}
@@ -0,0 +1,54 @@
+use chrono::{DateTime, Utc};
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Request};
+
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'?')
+    .add(b'<')
+    .add(b'>')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+pub fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes())
+        .unwrap_or_else(|_| Header::from_bytes(name.as_bytes(), &b""[..]).expect("valid header"))
+}
+
+pub fn header_value<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str())
+}
+
+pub fn http_date(time: SystemTime) -> String {
+    let time: DateTime<Utc> = time.into();
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let parsed = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    let secs = u64::try_from(parsed.timestamp()).ok()?;
+    Some(UNIX_EPOCH + std::time::Duration::from_secs(secs))
+}
+
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Turns a `/`-separated relative path into an absolute, percent-encoded URL path.
+pub fn encode_path(rel: &str) -> String {
+    rel.split('/')
+        .map(|segment| format!("/{}", utf8_percent_encode(segment, SEGMENT)))
+        .collect()
+}
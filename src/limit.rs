@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Forget idle clients once the tables grow past this many entries.
+const PRUNE_AT: usize = 10_000;
+
+// Failures further apart than this do not add up to a ban.
+pub const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket per client address: `rate` requests per second on average,
+/// with bursts of up to `burst`.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    /// `rate` must be positive: the wait `check` reports is divided by it.
+    pub fn new(rate: f64, burst: f64) -> Result<Self, String> {
+        if !(rate > 0.0 && rate.is_finite()) {
+            return Err(format!("request-rate must be a positive number, got {rate}"));
+        }
+        if !(burst > 0.0 && burst.is_finite()) {
+            return Err(format!("request-burst must be a positive number, got {burst}"));
+        }
+        Ok(RateLimiter {
+            rate,
+            burst: burst.max(1.0),
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Takes a token for `ip`, or says how long until one is available.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return Ok(());
+        };
+        if buckets.len() >= PRUNE_AT {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+    }
+}
+
+struct Strikes {
+    count: u32,
+    first: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Bans addresses that collect `threshold` 403/404 answers within
+/// [`STRIKE_WINDOW`], the signature of someone guessing paths.
+pub struct Bans {
+    threshold: u32,
+    ban_time: Duration,
+    strikes: Mutex<HashMap<IpAddr, Strikes>>,
+}
+
+impl Bans {
+    pub fn new(threshold: u32, ban_time: Duration) -> Self {
+        Bans {
+            threshold,
+            ban_time,
+            strikes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn ban_time(&self) -> Duration {
+        self.ban_time
+    }
+
+    /// Remaining ban time for `ip`, if it is banned.
+    pub fn banned(&self, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let strikes = self.strikes.lock().ok()?;
+        let until = strikes.get(&ip)?.banned_until?;
+        until.checked_duration_since(now).filter(|left| !left.is_zero())
+    }
+
+    /// Records a failed request; returns true when this one tipped `ip` into a ban.
+    pub fn strike(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let Ok(mut strikes) = self.strikes.lock() else {
+            return false;
+        };
+        if strikes.len() >= PRUNE_AT {
+            strikes.retain(|_, entry| match entry.banned_until {
+                Some(until) => until > now,
+                None => now.duration_since(entry.first) < STRIKE_WINDOW,
+            });
+        }
+
+        let entry = strikes.entry(ip).or_insert(Strikes {
+            count: 0,
+            first: now,
+            banned_until: None,
+        });
+        if entry.banned_until.is_some_and(|until| until > now) {
+            return false;
+        }
+        if entry.banned_until.is_some() || now.duration_since(entry.first) >= STRIKE_WINDOW {
+            *entry = Strikes {
+                count: 0,
+                first: now,
+                banned_until: None,
+            };
+        }
+        entry.count += 1;
+        if entry.count >= self.threshold {
+            entry.banned_until = Some(now + self.ban_time);
+            return true;
+        }
+        false
+    }
+}
+
+/// Whole seconds for a `Retry-After` header, never zero.
+pub fn retry_after(wait: Duration) -> String {
+    (wait.as_secs() + u64::from(wait.subsec_nanos() > 0)).max(1).to_string()
+}
+
+/// Caps how many requests one address may have queued or in progress.
+pub struct RequestLimit {
+    max: usize,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl RequestLimit {
+    pub fn new(max: usize) -> Self {
+        RequestLimit {
+            max,
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn acquire(&self, ip: IpAddr) -> Option<Slot> {
+        let mut counts = self.counts.lock().ok()?;
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(Slot {
+            ip,
+            counts: Arc::clone(&self.counts),
+        })
+    }
+}
+
+/// One request's share of its address's limit, given back on drop.
+pub struct Slot {
+    ip: IpAddr,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Ok(mut counts) = self.counts.lock()
+            && let Some(count) = counts.get_mut(&self.ip)
+        {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_rejects_rates_it_cannot_divide_by() {
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(RateLimiter::new(rate, 1.0).is_err(), "{rate}");
+        }
+        assert!(RateLimiter::new(1.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn rate_limiter_reports_the_wait_for_the_next_token() {
+        let limiter = RateLimiter::new(2.0, 1.0).unwrap();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(limiter.check(ip).is_ok());
+        let wait = limiter.check(ip).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(500), "{wait:?}");
+        assert!(limiter.check("192.0.2.2".parse().unwrap()).is_ok());
+    }
+}
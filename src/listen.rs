@@ -0,0 +1,114 @@
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use tiny_http::{ConfigListenAddr, ListenAddr, Server, ServerConfig, SslConfig};
+
+#[derive(Debug, Clone)]
+pub enum Bind {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Bind {
+    /// Accepts `ip:port`, `[v6]:port`, a bare IP or host name (which gets the
+    /// default port), or `unix:/path.sock`.
+    pub fn parse(value: &str, port: u16) -> Result<Bind, String> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix: needs a socket path".to_string());
+            }
+            return Ok(Bind::Unix(PathBuf::from(path)));
+        }
+        if let Ok(addr) = value.parse::<SocketAddr>() {
+            return Ok(Bind::Tcp(addr.to_string()));
+        }
+        let bare = value.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = bare.parse::<IpAddr>() {
+            return Ok(Bind::Tcp(SocketAddr::new(ip, port).to_string()));
+        }
+        if value.is_empty() {
+            return Err("--bind needs an address".to_string());
+        }
+        if value.contains(':') {
+            Ok(Bind::Tcp(value.to_string()))
+        } else {
+            Ok(Bind::Tcp(format!("{value}:{port}")))
+        }
+    }
+}
+
+pub struct Listener {
+    pub server: Server,
+    pub url: String,
+}
+
+pub fn bind(
+    binds: &[Bind],
+    port: u16,
+    ssl: Option<&SslConfig>,
+) -> Result<Vec<Listener>, Box<dyn Error + Send + Sync>> {
+    if binds.is_empty() {
+        let wildcard = Bind::Tcp(format!("[::]:{port}"));
+        return match listen(&wildcard, ssl) {
+            Ok(listener) => Ok(vec![listener]),
+            Err(e) => {
+                eprintln!("Cannot listen on [::]:{port} ({e}), falling back to 0.0.0.0");
+                Ok(vec![listen(&Bind::Tcp(format!("0.0.0.0:{port}")), ssl)?])
+            }
+        };
+    }
+
+    binds
+        .iter()
+        .map(|bind| {
+            listen(bind, ssl).map_err(|e| {
+                let name = match bind {
+                    Bind::Tcp(addr) => addr.clone(),
+                    Bind::Unix(path) => format!("unix:{}", path.display()),
+                };
+                format!("Cannot listen on {name}: {e}").into()
+            })
+        })
+        .collect()
+}
+
+fn listen(bind: &Bind, ssl: Option<&SslConfig>) -> Result<Listener, Box<dyn Error + Send + Sync>> {
+    let addr = match bind {
+        Bind::Tcp(addr) => ConfigListenAddr::from_socket_addrs(addr.as_str())?,
+        #[cfg(unix)]
+        Bind::Unix(path) => {
+            remove_stale_socket(path);
+            ConfigListenAddr::unix_from_path(path.clone())
+        }
+        #[cfg(not(unix))]
+        Bind::Unix(_) => return Err("Unix sockets are not supported on this platform".into()),
+    };
+    let server = Server::new(ServerConfig {
+        addr,
+        ssl: ssl.cloned(),
+    })?;
+
+    let scheme = if ssl.is_some() { "https" } else { "http" };
+    let url = match server.server_addr() {
+        ListenAddr::IP(addr) => format!("{scheme}://{addr}"),
+        #[cfg(unix)]
+        ListenAddr::Unix(addr) => match addr.as_pathname() {
+            Some(path) => format!("{scheme}+unix:{}", path.display()),
+            None => format!("{scheme}+unix"),
+        },
+    };
+    Ok(Listener { server, url })
+}
+
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixStream;
+
+    let is_socket = std::fs::symlink_metadata(path)
+        .map(|meta| meta.file_type().is_socket())
+        .unwrap_or(false);
+    if is_socket && UnixStream::connect(path).is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+}
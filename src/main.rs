@@ -1,44 +1,114 @@
+mod access_log;
+mod acl;
+mod auth;
+mod capability;
+mod conditional;
+mod config;
+mod denylist;
+mod encoding;
+mod glob;
+mod http;
+mod limit;
+mod listen;
+mod pool;
+mod mime;
+mod quota;
+mod range;
+mod resolve;
+mod share;
+mod shutdown;
+mod sign;
+mod throttle;
+mod tls;
+mod units;
+mod upload;
+
 use percent_encoding::percent_decode_str;
-use ctrlc;
 use std::borrow::Cow;
 use std::env;
-use std::fs::File;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::Ordering;
 use std::thread;
-use tiny_http::{Header, Method, Response, Server, StatusCode};
-use chrono::Local;
+use tiny_http::{Header, Method, Request, Response, ResponseBox, Server, StatusCode};
+use access_log::{Counted, Entry};
+use acl::{Acl, Cidr};
+use auth::Htpasswd;
+use capability::Capability;
+use conditional::{Etags, Outcome};
+use denylist::Denylist;
+use encoding::{Compression, Representation};
+use glob::Glob;
+use http::{header, header_value};
+use limit::{Bans, RequestLimit, RateLimiter, Slot};
+use listen::Bind;
+use pool::Pool;
+use quota::{Quotas, Reservation, Reserve};
+use range::{FileSlice, Selection};
+use resolve::{Opened, Root, Symlinks};
+use share::Shares;
+use shutdown::{Drained, Stats};
+use sign::{Signer, Verdict};
+use throttle::{Throttle, Throttled};
+use upload::Uploads;
 
-fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut force = false;
-    let mut port: Option<u16> = None;
+struct Context {
+    root: Root,
+    acl: Acl,
+    capability: Option<Capability>,
+    signer: Option<Signer>,
+    htpasswd: Option<Htpasswd>,
+    log_format: access_log::Format,
+    etags: Etags,
+    uploads: Option<Uploads>,
+    inline: Vec<Glob>,
+    inline_scriptable: bool,
+    compress: Option<Compression>,
+    rate_limiter: Option<RateLimiter>,
+    bans: Option<Bans>,
+    throttle: Arc<Throttle>,
+    request_limit: Option<RequestLimit>,
+    stats: Stats,
+    quotas: Option<Quotas>,
+    shares: Option<Shares>,
+    denylist: Denylist,
+    stealth: bool,
+}
 
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut args = env::args().skip(1).peekable();
-    while let Some(arg) = args.next() {
-        if arg == "--force" {
-            force = true;
-            continue;
-        }
-        if arg.starts_with('-') {
-            return Err(format!("Unknown flag: {arg}").into());
-        }
+    if args.peek().is_some_and(|arg| arg == "sign") {
+        args.next();
+        return sign::run(args);
+    }
 
-        if port.is_none() {
-            let value: u16 = arg
-                .parse()
-                .map_err(|_| "Port must be a number between 1 and 65535")?;
-            if value == 0 {
-                return Err("Port must be between 1 and 65535".into());
-            }
-            port = Some(value);
-            continue;
-        }
-        return Err("Too many positional arguments".into());
+    let args: Vec<String> = args.collect();
+    let loaded = config::load(args.iter().cloned())?;
+    if loaded.print_config {
+        print!("{}", config::to_toml(&loaded.config));
+        return Ok(());
     }
+    let config = loaded.config;
 
     let base_dir = env::current_dir()?;
     let base_dir = base_dir.canonicalize()?;
 
-    if !force && is_dangerous_dir(&base_dir) {
+    // --once on its own hands out just the files it names, like `nsv share`;
+    // only --max-downloads opens up the rest of the directory.
+    let mut shared = config.share.clone();
+    if config.max_downloads.is_none() {
+        shared.extend(config.once.iter().cloned());
+    }
+    let shares = if shared.is_empty() {
+        None
+    } else {
+        Some(Shares::new(&shared)?)
+    };
+
+    // Sharing single files never exposes the rest of the directory.
+    if shares.is_none() && !config.force.unwrap_or(false) && is_dangerous_dir(&base_dir) {
         eprintln!(
             "Refusing to serve dangerous directory: {}",
             base_dir.display()
@@ -47,112 +117,839 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         std::process::exit(1);
     }
 
-    let port = port.unwrap_or(8000);
-    let addr = format!("[::]:{port}");
-    let server = Server::http(&addr)?;
+    let mut acl = Acl::default();
+    for value in &config.allow {
+        let cidr = Cidr::parse(value)
+            .ok_or_else(|| format!("allow expects an IP address or CIDR range, got {value}"))?;
+        acl.allow(cidr);
+    }
+    for value in &config.deny {
+        let cidr = Cidr::parse(value)
+            .ok_or_else(|| format!("deny expects an IP address or CIDR range, got {value}"))?;
+        acl.deny(cidr);
+    }
+    let deny_paths = config.deny_path.iter().map(|pattern| Glob::new(pattern)).collect();
+    let mut denylist = Denylist::new(config.allow_hidden.unwrap_or(false), deny_paths);
+    // nsv's own secrets are never handed out, wherever they were put.
+    for secret in [&config.auth_file, &config.sign_key, &config.tls_key]
+        .into_iter()
+        .flatten()
+    {
+        denylist.protect(&base_dir, secret);
+    }
+
+    let capability = match (config.token.unwrap_or(false), config.token_per_file.unwrap_or(false)) {
+        (false, false) => None,
+        (true, false) => Some(Capability::per_run()?),
+        (false, true) => Some(Capability::per_file()?),
+        (true, true) => return Err("--token and --token-per-file are mutually exclusive".into()),
+    };
+
+    let signer = match &config.sign_key {
+        Some(path) => Some(Signer::from_file(path)?),
+        None => None,
+    };
+
+    let htpasswd = match &config.auth_file {
+        Some(path) => Some(Htpasswd::load(path)?),
+        None => None,
+    };
+
+    let uploads = match &config.upload_dir {
+        Some(dir) => {
+            let dir = dir
+                .canonicalize()
+                .map_err(|e| format!("Cannot use upload directory {}: {e}", dir.display()))?;
+            if !dir.is_dir() {
+                return Err(format!("Upload directory {} is not a directory", dir.display()).into());
+            }
+            // Either way round, anyone allowed to upload could publish files
+            // through the download side.
+            if dir.starts_with(&base_dir) || base_dir.starts_with(&dir) {
+                return Err(format!(
+                    "Upload directory {} must be outside the served directory {} and not contain it",
+                    dir.display(),
+                    base_dir.display()
+                )
+                .into());
+            }
+            // Links out of the upload directory are never followed, even
+            // with --symlinks follow.
+            let symlinks = match config.symlinks {
+                Some(Symlinks::Deny) => Symlinks::Deny,
+                _ => Symlinks::Inside,
+            };
+            Some(Uploads {
+                root: Root::new(dir, symlinks, false)?,
+                max_size: config.upload_max_size.unwrap_or(config::DEFAULT_UPLOAD_MAX_SIZE).0,
+            })
+        }
+        None => None,
+    };
+
+    let rate_limiter = config
+        .request_rate
+        .map(|rate| RateLimiter::new(rate, config.request_burst.unwrap_or(rate.max(1.0))))
+        .transpose()?;
+    let bans = match config.ban_threshold {
+        Some(threshold) if threshold > 0 => Some(Bans::new(
+            threshold,
+            config.ban_time.unwrap_or(config::DEFAULT_BAN_TIME).0,
+        )),
+        _ => None,
+    };
+
+    let throttle = Arc::new(Throttle::default());
+    apply_rate_limits(&throttle, &config);
+
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::load(cert, key)?),
+        (None, None) if config.tls.unwrap_or(false) => Some(tls::self_signed()?),
+        (None, None) => None,
+        _ => return Err("--tls-cert and --tls-key must be given together".into()),
+    };
+
+    let port = config.port.unwrap_or(config::DEFAULT_PORT);
+    let binds = config
+        .bind
+        .iter()
+        .map(|value| Bind::parse(value, port))
+        .collect::<Result<Vec<_>, _>>()?;
+    let listeners = listen::bind(&binds, port, tls.as_ref().map(|tls| &tls.config))?;
+
+    #[cfg(unix)]
+    reload_on_hangup(args, Arc::clone(&throttle))?;
+
+    let (stop, signals) = mpsc::channel();
+    shutdown::forward_signals(stop.clone())?;
+
+    let quotas = if config.once.is_empty() && config.max_downloads.is_none() {
+        None
+    } else {
+        let mut files = HashMap::new();
+        if let Some(limit) = config.max_downloads {
+            match &shares {
+                Some(shares) => {
+                    for (_, path) in shares.links() {
+                        files.insert(path.to_path_buf(), limit);
+                    }
+                }
+                None => {
+                    for rel in capability::list_files(&base_dir) {
+                        if !denylist.denies(&rel) {
+                            files.insert(base_dir.join(rel), limit);
+                        }
+                    }
+                }
+            }
+        }
+        for path in &config.once {
+            let file = path
+                .canonicalize()
+                .map_err(|e| format!("Cannot share {}: {e}", path.display()))?;
+            match &shares {
+                Some(shares) if !shares.links().iter().any(|(_, shared)| *shared == file) => {
+                    return Err(format!("{} is not one of the shared files", path.display()).into());
+                }
+                None if !file.starts_with(&base_dir) || !file.is_file() => {
+                    return Err(
+                        format!("{} is not a file below {}", path.display(), base_dir.display()).into(),
+                    );
+                }
+                _ => {}
+            }
+            files.insert(file, 1);
+        }
+        Some(Quotas::new(files, config.max_downloads, stop))
+    };
+
+    let what = match &shares {
+        Some(_) => "selected files".to_string(),
+        None => base_dir.display().to_string(),
+    };
+    for listener in &listeners {
+        match &tls {
+            Some(tls) => println!(
+                "Serving {} on {} (SHA-256 fingerprint {})",
+                what,
+                listener.url,
+                tls.fingerprint
+            ),
+            None => println!("Serving {} on {}", what, listener.url),
+        }
+    }
+    match &shares {
+        Some(shares) => {
+            println!("Only these files are shared:");
+            for (rel, path) in shares.links() {
+                let url = match &capability {
+                    Some(capability) => capability.url_path(&rel),
+                    None => http::encode_path(&rel),
+                };
+                println!("  {url}  ({})", path.display());
+            }
+        }
+        None => println!("Index is disabled; only direct file paths are allowed."),
+    }
+    let one_file_system = config.one_file_system.unwrap_or(false);
+    let mounts = match shares {
+        Some(_) => Vec::new(),
+        None => resolve::mount_points_below(&base_dir),
+    };
+    if !mounts.is_empty() {
+        println!("Warning: other filesystems are mounted below {}:", base_dir.display());
+        for mount in &mounts {
+            println!("  {}", mount.display());
+        }
+        if one_file_system {
+            println!("Files on them are not served (--one-file-system).");
+        } else {
+            println!("Pass --one-file-system to keep them out.");
+        }
+    }
+    if !acl.is_empty() {
+        println!("Client address filtering is enabled.");
+    }
+    if let Some(rate) = config.request_rate {
+        println!("Clients are limited to {rate} requests per second.");
+    }
+    if let Some(threshold) = config.ban_threshold.filter(|threshold| *threshold > 0) {
+        println!(
+            "Clients with {} forbidden or missing paths within {}s are banned for {}.",
+            threshold,
+            limit::STRIKE_WINDOW.as_secs(),
+            config.ban_time.unwrap_or(config::DEFAULT_BAN_TIME)
+        );
+    }
+    if let Some(max) = config.max_requests_per_ip {
+        println!("Clients may have at most {max} requests in flight.");
+    }
+    if config.rate_limit.is_some() || config.global_rate_limit.is_some() {
+        println!(
+            "Transfers are limited to {} each and {} in total.",
+            describe_rate(config.rate_limit),
+            describe_rate(config.global_rate_limit)
+        );
+    }
+    if let Some(uploads) = &uploads {
+        println!(
+            "Uploads (PUT, multipart POST) go to {}, up to {} each.",
+            uploads.root.path().display(),
+            units::ByteSize(uploads.max_size)
+        );
+    }
+    if signer.is_some() {
+        println!("Only links signed with `nsv sign` are accepted.");
+    }
+    match &capability {
+        Some(Capability::PerRun(token)) => {
+            println!("Files are only reachable below /{token}/");
+        }
+        Some(capability) if shares.is_none() => {
+            for rel in capability::list_files(&base_dir) {
+                if !denylist.denies(&rel) {
+                    println!("  {}", capability.url_path(&rel));
+                }
+            }
+        }
+        _ => {}
+    }
+    if let Some(limit) = config.max_downloads {
+        println!("Every file can be downloaded {limit} times.");
+    }
+    for path in &config.once {
+        let Ok(file) = path.canonicalize() else {
+            continue;
+        };
+        let rel = match &shares {
+            Some(shares) => shares
+                .links()
+                .into_iter()
+                .find(|(_, shared)| *shared == file)
+                .map(|(rel, _)| rel),
+            None => file
+                .strip_prefix(&base_dir)
+                .ok()
+                .map(|rel| rel.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/")),
+        };
+        if let Some(rel) = rel {
+            let url = match &capability {
+                Some(capability) => capability.url_path(&rel),
+                None => http::encode_path(&rel),
+            };
+            println!("  {url} can be downloaded once.");
+        }
+    }
+    if quotas.is_some() {
+        println!("nsv exits once every download has been used up.");
+    }
 
-    ctrlc::set_handler(|| {
-        std::process::exit(0);
-    })?;
+    let ctx = Arc::new(Context {
+        root: Root::new(
+            base_dir,
+            config.symlinks.unwrap_or_default(),
+            one_file_system,
+        )?,
+        acl,
+        capability,
+        signer,
+        htpasswd,
+        log_format: config.log_format.unwrap_or_default(),
+        etags: Etags::new(config.etag.unwrap_or_default()),
+        uploads,
+        inline: config.inline.iter().map(|pattern| Glob::new(pattern)).collect(),
+        inline_scriptable: config.inline_scriptable.unwrap_or(false),
+        compress: config.compress,
+        rate_limiter,
+        bans,
+        throttle,
+        request_limit: config.max_requests_per_ip.map(RequestLimit::new),
+        stats: Stats::new(),
+        quotas,
+        shares,
+        denylist,
+        stealth: config.stealth.unwrap_or(false),
+    });
+    let pool = {
+        let ctx = Arc::clone(&ctx);
+        Arc::new(Pool::new(
+            config.workers.unwrap_or(config::DEFAULT_WORKERS),
+            config.queue.unwrap_or(config::DEFAULT_QUEUE),
+            move |(request, _slot): Job| handle_request(&ctx, request),
+        ))
+    };
+    let servers: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| Arc::new(listener.server))
+        .collect();
+    let accept_loops: Vec<_> = servers
+        .iter()
+        .map(|server| {
+            let server = Arc::clone(server);
+            let ctx = Arc::clone(&ctx);
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || serve(&server, ctx, pool))
+        })
+        .collect();
 
-    println!("Serving {} on http://{}", base_dir.display(), addr);
-    println!("Index is disabled; only direct file paths are allowed.");
+    let _ = signals.recv();
+    let timeout = config.shutdown_timeout.unwrap_or(config::DEFAULT_SHUTDOWN_TIMEOUT);
+    println!(
+        "Shutting down, waiting up to {} for {} requests in flight. Interrupt again to quit now.",
+        timeout,
+        pool.pending()
+    );
+    ctx.stats.stopping.store(true, Ordering::SeqCst);
+    for server in &servers {
+        server.unblock();
+    }
+    for accept_loop in accept_loops {
+        let _ = accept_loop.join();
+    }
+    // Dropping the last handle closes the listening sockets.
+    drop(servers);
 
-    for request in server.incoming_requests() {
-        let base_dir = base_dir.clone();
-        thread::spawn(move || handle_request(base_dir, request));
+    let drained = shutdown::drain(|| pool.pending(), timeout.0, &signals);
+    println!("{}", ctx.stats.summary(pool.pending()));
+    match drained {
+        Drained::Idle => Ok(()),
+        Drained::TimedOut | Drained::Forced => std::process::exit(1),
     }
+}
+
+fn apply_rate_limits(throttle: &Throttle, config: &config::Config) {
+    throttle.set(
+        config.rate_limit.map_or(0, |rate| rate.0),
+        config.global_rate_limit.map_or(0, |rate| rate.0),
+    );
+}
+
+fn describe_rate(rate: Option<units::ByteRate>) -> String {
+    rate.filter(|rate| rate.0 > 0)
+        .map_or("unlimited".to_string(), |rate| rate.to_string())
+}
 
+/// Re-reads the configuration on SIGHUP and applies the new rate limits.
+/// Everything else keeps the values nsv started with.
+#[cfg(unix)]
+fn reload_on_hangup(
+    args: Vec<String>,
+    throttle: Arc<Throttle>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use signal_hook::consts::SIGHUP;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            match config::load(args.iter().cloned()) {
+                Ok(loaded) => {
+                    let config = loaded.config;
+                    apply_rate_limits(&throttle, &config);
+                    access_log::event(
+                        config.log_format.unwrap_or_default(),
+                        "reload",
+                        &[
+                            ("rate_limit", describe_rate(config.rate_limit)),
+                            ("global_rate_limit", describe_rate(config.global_rate_limit)),
+                        ],
+                    );
+                }
+                Err(e) => eprintln!("Reload failed, keeping the current limits: {e}"),
+            }
+        }
+    });
     Ok(())
 }
 
-fn handle_request(base_dir: PathBuf, request: tiny_http::Request) {
-    let method = request.method().clone();
-    if method != Method::Get && method != Method::Head {
-        let _ = request.respond(Response::empty(StatusCode(405)));
-        return;
+// A queued request and its hold on the client's request limit.
+type Job = (Request, Option<Slot>);
+
+fn serve(server: &Server, ctx: Arc<Context>, pool: Arc<Pool<Job>>) {
+    loop {
+        let request = match server.recv() {
+            Ok(request) => request,
+            Err(_) if ctx.stats.stopping.load(Ordering::SeqCst) => return,
+            Err(_) => continue,
+        };
+        let slot = match (&ctx.request_limit, request.remote_addr()) {
+            (Some(limit), Some(addr)) => match limit.acquire(addr.ip().to_canonical()) {
+                Some(slot) => Some(slot),
+                None => {
+                    shed(&ctx, request, 429);
+                    continue;
+                }
+            },
+            _ => None,
+        };
+        if let Err((request, _slot)) = pool.submit((request, slot)) {
+            shed(&ctx, request, 503);
+        }
     }
+}
 
-    let remote = request
-        .remote_addr()
-        .map(|addr| addr.to_string())
-        .unwrap_or_else(|| "unknown".to_string());
+/// Turns a request away from the accept loop without queueing it.
+fn shed(ctx: &Context, request: Request, status: u16) {
+    let entry = Entry::new(&request);
+    let response = Response::empty(StatusCode(status))
+        .with_header(header("Retry-After", "1"))
+        .boxed();
+    send(ctx, request, entry, response);
+}
 
-    let url = request.url();
-    let path = url.split('?').next().unwrap_or(url);
-    if path == "/" || path.ends_with('/') {
-        let _ = request.respond(Response::empty(StatusCode(403)));
-        return;
+fn flag_value(
+    args: &mut impl Iterator<Item = String>,
+    flag: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    args.next()
+        .ok_or_else(|| format!("{flag} requires a value").into())
+}
+
+fn handle_request(ctx: &Context, mut request: Request) {
+    let mut entry = Entry::new(&request);
+    let mut reservation = None;
+    let response = respond(ctx, &mut request, &mut entry, &mut reservation);
+    let entry = send(ctx, request, entry, response);
+    if let Some(reservation) = reservation
+        && entry.status == 200
+        && entry.complete
+    {
+        reservation.commit();
     }
+}
 
-    let rel = path.trim_start_matches('/');
-    if rel.is_empty() {
-        let _ = request.respond(Response::empty(StatusCode(403)));
-        return;
+fn send(ctx: &Context, request: Request, mut entry: Entry, response: ResponseBox) -> Entry {
+    let ip = request.remote_addr().map(|addr| addr.ip().to_canonical());
+    let status = response.status_code();
+    let expected = response.data_length();
+    let chunked_threshold = response.chunked_threshold();
+    let headers = response.headers().to_vec();
+    let (body, written) = Counted::new(response.into_reader());
+    let body: Box<dyn Read + Send> = Box::new(Throttled::new(body, Arc::clone(&ctx.throttle)));
+    let mut response = Response::new(status, headers, body, expected, None)
+        .with_chunked_threshold(chunked_threshold);
+    response.add_header(header("X-Request-Id", &entry.id));
+    response.add_header(header("X-Content-Type-Options", "nosniff"));
+
+    let method = request.method().clone();
+    let bodiless = method == Method::Head || matches!(status.0, 100..=199 | 204 | 304);
+    let sent = request.respond(response).is_ok();
+    entry.status = status.0;
+    entry.bytes = written.load(Ordering::Relaxed);
+    entry.complete = sent && (bodiless || expected.is_none_or(|len| entry.bytes == len as u64));
+    entry.finish();
+    entry.write(ctx.log_format);
+    ctx.stats.record(
+        entry.bytes,
+        entry.complete && method == Method::Get && matches!(status.0, 200 | 206),
+    );
+
+    if let Some(bans) = &ctx.bans
+        && matches!(status.0, 403 | 404)
+        && let Some(ip) = ip
+        && bans.strike(ip)
+    {
+        access_log::event(
+            ctx.log_format,
+            "ban",
+            &[
+                ("ip", ip.to_string()),
+                ("for", units::Interval(bans.ban_time()).to_string()),
+            ],
+        );
     }
+    entry
+}
 
-    let rel = decode_path(rel);
-    if rel.contains('\u{0000}') {
-        let _ = request.respond(Response::empty(StatusCode(400)));
-        return;
+fn respond(
+    ctx: &Context,
+    request: &mut Request,
+    entry: &mut Entry,
+    reservation: &mut Option<Reservation>,
+) -> ResponseBox {
+    if !ctx.acl.permits(request.remote_addr().map(|addr| addr.ip())) {
+        return Response::empty(StatusCode(403)).boxed();
     }
 
-    let candidate = base_dir.join(rel.as_ref());
-    let candidate = match candidate.canonicalize() {
-        Ok(path) => path,
-        Err(_) => {
-            let _ = request.respond(Response::empty(StatusCode(404)));
-            return;
+    if let Some(ip) = request.remote_addr().map(|addr| addr.ip().to_canonical()) {
+        let wait = ctx
+            .bans
+            .as_ref()
+            .and_then(|bans| bans.banned(ip))
+            .or_else(|| ctx.rate_limiter.as_ref().and_then(|limiter| limiter.check(ip).err()));
+        if let Some(wait) = wait {
+            return Response::empty(StatusCode(429))
+                .with_header(header("Retry-After", &limit::retry_after(wait)))
+                .boxed();
         }
+    }
+
+    let method = request.method().clone();
+    let upload = matches!(method, Method::Put | Method::Post);
+    if method != Method::Get && method != Method::Head && !(upload && ctx.uploads.is_some()) {
+        return Response::empty(StatusCode(405)).boxed();
+    }
+
+    if let Some(htpasswd) = &ctx.htpasswd {
+        match htpasswd.authenticate(header_value(request, "Authorization")) {
+            Some(user) => entry.user = Some(user),
+            None => {
+                return Response::empty(StatusCode(401))
+                    .with_header(header("WWW-Authenticate", auth::CHALLENGE))
+                    .boxed();
+            }
+        }
+    }
+
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let path = match &ctx.capability {
+        Some(capability) => match capability.strip(path) {
+            Some(path) => path,
+            None => {
+                return Response::empty(StatusCode(404)).boxed();
+            }
+        },
+        None => path,
     };
 
-    if !candidate.starts_with(&base_dir) {
-        let _ = request.respond(Response::empty(StatusCode(403)));
-        return;
+    let rel = decode_path(path.trim_start_matches('/'));
+    if rel.contains('\u{0000}') {
+        return Response::empty(StatusCode(400)).boxed();
     }
-    if candidate.is_dir() {
-        let _ = request.respond(Response::empty(StatusCode(403)));
-        return;
+
+    if let Some(signer) = &ctx.signer {
+        let status = match signer.verify(&rel, query) {
+            Verdict::Valid => None,
+            Verdict::Invalid => Some(403),
+            Verdict::Expired => Some(410),
+        };
+        if let Some(status) = status {
+            return Response::empty(StatusCode(status)).boxed();
+        }
+    }
+
+    if upload && let Some(uploads) = &ctx.uploads {
+        return upload::receive(uploads, request, &rel, entry);
     }
 
+    let opened = match &ctx.shares {
+        Some(shares) => match shares.open(&rel) {
+            Some(Ok(opened)) => opened,
+            Some(Err(e)) => {
+                return fs_error(ctx, entry, e);
+            }
+            None => {
+                return Response::empty(StatusCode(404)).boxed();
+            }
+        },
+        None => {
+            if path == "/" || path.ends_with('/') || rel.is_empty() {
+                return Response::empty(StatusCode(403)).boxed();
+            }
+            if ctx.denylist.denies(&rel) {
+                entry.file = Some(ctx.root.path().join(rel.as_ref()));
+                return Response::empty(StatusCode(404)).boxed();
+            }
+            let opened = match ctx.root.open(&rel) {
+                Ok(opened) => opened,
+                Err(e) => {
+                    return fs_error(ctx, entry, e);
+                }
+            };
+            // Check where the name really led, too.
+            let resolved = ctx.root.relative(&opened.path).unwrap_or_default();
+            if ctx.denylist.denies(&resolved) {
+                entry.file = Some(opened.path);
+                return Response::empty(StatusCode(404)).boxed();
+            }
+            opened
+        }
+    };
+    let Opened {
+        file,
+        path: candidate,
+    } = opened;
+
+    entry.file = Some(candidate.clone());
+
+    let meta = match file.metadata() {
+        Ok(meta) => meta,
+        Err(e) => {
+            return fs_error(ctx, entry, e);
+        }
+    };
+    // Quota'd files are only ever sent whole, so that a finished download
+    // is what gets counted.
+    let limited = match &ctx.quotas {
+        Some(quotas) if method == Method::Get => match quotas.reserve(&candidate) {
+            Reserve::Unlimited => false,
+            Reserve::Granted(granted) => {
+                *reservation = Some(granted);
+                true
+            }
+            Reserve::Exhausted => {
+                return Response::empty(StatusCode(410)).boxed();
+            }
+        },
+        Some(quotas) if quotas.exhausted(&candidate) => {
+            return Response::empty(StatusCode(410)).boxed();
+        }
+        _ => false,
+    };
+    let range = if limited { None } else { header_value(request, "Range") };
+
+    let content_type = mime::detect(&candidate, &file);
+    let scriptable = mime::is_scriptable(content_type);
+    let shown_rel = ctx
+        .root
+        .relative(&candidate)
+        .unwrap_or_else(|| candidate.to_string_lossy().into_owned());
+    let inline = ctx.inline.iter().any(|glob| glob.matches(&shown_rel))
+        && (!scriptable || ctx.inline_scriptable);
+    let kind = if inline { "inline" } else { "attachment" };
+
     let file_name = candidate
         .file_name()
         .and_then(|s| s.to_str())
         .unwrap_or("download");
-    let disposition = format!("attachment; filename=\"{}\"", file_name);
-    let header = Header::from_bytes(&b"Content-Disposition"[..], disposition)
-        .unwrap_or_else(|_| Header::from_bytes(&b"Content-Disposition"[..], "attachment")
+    let disposition = format!("{}; filename=\"{}\"", kind, file_name);
+    let disposition = Header::from_bytes(&b"Content-Disposition"[..], disposition)
+        .unwrap_or_else(|_| Header::from_bytes(&b"Content-Disposition"[..], kind)
             .expect("valid header"));
 
-    if method == Method::Head {
-        let len = match std::fs::metadata(&candidate) {
-            Ok(meta) => meta.len().to_string(),
-            Err(_) => {
-                let _ = request.respond(Response::empty(StatusCode(404)));
-                return;
+    // Without chunked framing tiny_http would buffer the whole compressed
+    // body to learn its length, so only compress on the fly when it can
+    // stream: HTTP/1.1 and no TE header asking for something else.
+    let streams = *request.http_version() > (1, 0) && header_value(request, "TE").is_none();
+    let representation = encoding::negotiate(
+        header_value(request, "Accept-Encoding"),
+        &ctx.root,
+        &candidate,
+        &meta,
+        content_type,
+        ctx.compress.filter(|_| streams),
+        range.is_some(),
+    );
+    let mut encoding_headers = vec![header("Vary", "Accept-Encoding")];
+    let mut on_the_fly = None;
+    let (served, file) = match representation {
+        Representation::Identity => (candidate, file),
+        Representation::Precompressed { file, path, coding } => {
+            encoding_headers.push(header("Content-Encoding", coding));
+            (path, file)
+        }
+        Representation::OnTheFly(compression) => {
+            encoding_headers.push(header("Content-Encoding", compression.token()));
+            on_the_fly = Some(compression);
+            (candidate, file)
+        }
+    };
+    let meta = match file.metadata() {
+        Ok(meta) => meta,
+        Err(e) => {
+            return fs_error(ctx, entry, e);
+        }
+    };
+    entry.file = Some(served.clone());
+    let len = meta.len();
+    let mut validators = match ctx.etags.validators(&served, &file, &meta) {
+        Ok(validators) => validators,
+        Err(e) => {
+            return fs_error(ctx, entry, e);
+        }
+    };
+    if let Some(compression) = on_the_fly {
+        validators.etag = encoding::tag_etag(&validators.etag, compression.token());
+    }
+
+    match conditional::evaluate(request, &validators) {
+        Outcome::Proceed => {}
+        Outcome::NotModified => {
+            // No body goes out with a 304, but Content-Length should still
+            // describe the representation rather than read 0. Past the
+            // chunked threshold tiny_http would drop it for chunked framing.
+            let mut response = Response::empty(StatusCode(304)).with_chunked_threshold(usize::MAX);
+            if on_the_fly.is_none() {
+                response.add_header(header("Content-Length", &len.to_string()));
+            }
+            for header in validators.headers().into_iter().chain(encoding_headers) {
+                response.add_header(header);
+            }
+            return response.boxed();
+        }
+        Outcome::PreconditionFailed => {
+            return Response::empty(StatusCode(412)).boxed();
+        }
+    }
+
+    // Ranges of an on-the-fly stream are not served, so resuming one must
+    // start over rather than append file bytes to a partial gzip.
+    let accept_ranges = if limited || on_the_fly.is_some() { "none" } else { "bytes" };
+    let mut headers = vec![disposition, header("Accept-Ranges", accept_ranges)];
+    headers.extend(validators.headers());
+    if inline && scriptable {
+        // Opted in with --inline-scriptable; still keep it off our origin.
+        headers.push(header("Content-Security-Policy", "sandbox"));
+    }
+    headers.push(header("Content-Type", content_type));
+    headers.extend(encoding_headers);
+
+    if let Some(compression) = on_the_fly {
+        // The compressed length is only known once it has been sent.
+        let body: Box<dyn Read + Send> = if method == Method::Head {
+            Box::new(std::io::empty())
+        } else {
+            match compression.encoder(Box::new(FileSlice::new(file, 0, len))) {
+                Ok(body) => body,
+                Err(_) => {
+                    return Response::empty(StatusCode(500)).boxed();
+                }
             }
         };
-        log_download(&remote, &candidate);
-        let len_header = Header::from_bytes(&b"Content-Length"[..], len)
+        return Response::new(StatusCode(200), headers, body, None, None).boxed();
+    }
+
+    if method == Method::Head {
+        let len_header = Header::from_bytes(&b"Content-Length"[..], len.to_string())
             .unwrap_or_else(|_| Header::from_bytes(&b"Content-Length"[..], "0")
                 .expect("valid header"));
-        let response = Response::empty(StatusCode(200))
-            .with_header(header)
-            .with_header(len_header);
-        let _ = request.respond(response);
-        return;
+        // Same as for 304: keep the length however large the file is.
+        let mut response = Response::empty(StatusCode(200))
+            .with_header(len_header)
+            .with_chunked_threshold(usize::MAX);
+        for header in headers {
+            response.add_header(header);
+        }
+        return response.boxed();
     }
 
-    let file = match File::open(&candidate) {
-        Ok(file) => file,
-        Err(_) => {
-            let _ = request.respond(Response::empty(StatusCode(404)));
-            return;
+    let selection = range::select(
+        range,
+        header_value(request, "If-Range"),
+        len,
+        &validators,
+    );
+    match selection {
+        Selection::Full => file_response(
+            StatusCode(200),
+            headers,
+            Box::new(FileSlice::new(file, 0, len)),
+            len,
+        ),
+        Selection::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            headers.push(header("Content-Range", &range.content_range(len)));
+            file_response(
+                StatusCode(206),
+                headers,
+                Box::new(FileSlice::new(file, range.start, range.len())),
+                range.len(),
+            )
+        }
+        Selection::Partial(ranges) => {
+            let multipart = match range::multipart(&file, &ranges, len, content_type) {
+                Ok(multipart) => multipart,
+                Err(_) => {
+                    return Response::empty(StatusCode(500)).boxed();
+                }
+            };
+            let content_type = format!("multipart/byteranges; boundary={}", multipart.boundary);
+            headers.retain(|h| !h.field.equiv("Content-Type"));
+            headers.push(header("Content-Type", &content_type));
+            file_response(
+                StatusCode(206),
+                headers,
+                Box::new(multipart.body),
+                multipart.len,
+            )
         }
+        Selection::Unsatisfiable => {
+            Response::empty(StatusCode(416))
+                .with_header(header("Accept-Ranges", "bytes"))
+                .with_header(header("Content-Range", &format!("bytes */{len}")))
+                .boxed()
+        }
+    }
+}
+
+/// Answers a failed filesystem call with 404, 403 or 500 depending on what
+/// went wrong, or always 404 with --stealth. Real failures are logged, since
+/// the client is not told why.
+fn fs_error(ctx: &Context, entry: &Entry, error: std::io::Error) -> ResponseBox {
+    use std::io::ErrorKind;
+
+    // Names too long to exist are as missing as any other; symlink loops
+    // already come back from resolve as NotFound.
+    let missing = matches!(
+        error.kind(),
+        ErrorKind::NotFound | ErrorKind::NotADirectory | ErrorKind::InvalidFilename
+    );
+    let status = match error.kind() {
+        _ if missing || ctx.stealth => 404,
+        ErrorKind::PermissionDenied => 403,
+        _ => 500,
     };
-    log_download(&remote, &candidate);
-    let response = Response::from_file(file).with_header(header);
-    let _ = request.respond(response);
+    if !missing && !resolve::is_refusal(&error) {
+        access_log::event(
+            ctx.log_format,
+            "error",
+            &[("id", entry.id.clone()), ("error", error.to_string())],
+        );
+    }
+    Response::empty(StatusCode(status)).boxed()
+}
+
+fn file_response(
+    status: StatusCode,
+    headers: Vec<Header>,
+    body: Box<dyn Read + Send>,
+    len: u64,
+) -> ResponseBox {
+    let len = usize::try_from(len).ok();
+    // Sent with its length however large, so clients can show progress and
+    // resume; tiny_http would otherwise go chunked from 32KiB on.
+    Response::new(status, headers, body, len, None).with_chunked_threshold(usize::MAX)
 }
 
 fn decode_path(input: &str) -> Cow<'_, str> {
@@ -168,10 +965,10 @@ fn is_dangerous_dir(path: &Path) -> bool {
         return true;
     }
 
-    if let Some(home) = home_dir() {
-        if path == home {
-            return true;
-        }
+    if let Some(home) = home_dir()
+        && path == home
+    {
+        return true;
     }
 
     false
@@ -187,7 +984,3 @@ fn home_dir() -> Option<PathBuf> {
     None
 }
 
-fn log_download(remote: &str, file: &Path) {
-    let ts = Local::now().format("%Y%m%d-%H:%M:%S%z");
-    println!("ts={} ip={} file={}", ts, remote, file.display());
-}
@@ -0,0 +1,68 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A fixed set of worker threads fed through a bounded queue. This bounds
+/// the requests being handled, not connections: tiny_http reads every
+/// connection on a thread of its own before a request gets here.
+pub struct Pool<T> {
+    queue: SyncSender<T>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl<T: Send + 'static> Pool<T> {
+    pub fn new(workers: usize, queue: usize, handler: impl Fn(T) + Send + Sync + 'static) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+        let pending = Arc::new(AtomicUsize::new(0));
+        for _ in 0..workers.max(1) {
+            let receiver = Arc::clone(&receiver);
+            let handler = Arc::clone(&handler);
+            let pending = Arc::clone(&pending);
+            thread::spawn(move || work(&receiver, &*handler, &pending));
+        }
+        Pool {
+            queue: sender,
+            pending,
+        }
+    }
+
+    /// Queues `item`, handing it back if every worker is busy and the queue is full.
+    pub fn submit(&self, item: T) -> Result<(), T> {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        match self.queue.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item) | TrySendError::Disconnected(item)) => {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                Err(item)
+            }
+        }
+    }
+
+    /// Items queued or being handled right now.
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+}
+
+fn work<T>(receiver: &Mutex<Receiver<T>>, handler: &dyn Fn(T), pending: &AtomicUsize) {
+    loop {
+        let item = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        match item {
+            // Keeps the worker going after a panicking request in builds that
+            // unwind; the release profile aborts on panic, taking the whole
+            // server down instead.
+            Ok(item) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| handler(item)));
+                pending.fetch_sub(1, Ordering::SeqCst);
+            }
+            Err(_) => return,
+        }
+    }
+}
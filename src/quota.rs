@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+struct Share {
+    limit: u32,
+    used: u32,
+    in_flight: u32,
+}
+
+struct State {
+    shares: HashMap<PathBuf, Share>,
+    done: Option<Sender<()>>,
+}
+
+/// Download counts for files that may only be fetched so many times.
+///
+/// A GET reserves one of the remaining downloads up front so that parallel
+/// requests cannot overshoot the limit; the reservation only turns into a
+/// used download once the whole file went out.
+pub struct Quotas {
+    default: Option<u32>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Quotas {
+    /// `files` get their own limit; with a `default`, every other file
+    /// gets that one the first time it is requested. `done` hears once
+    /// every known share is used up.
+    pub fn new(files: HashMap<PathBuf, u32>, default: Option<u32>, done: Sender<()>) -> Self {
+        let shares = files
+            .into_iter()
+            .map(|(path, limit)| {
+                let share = Share {
+                    limit,
+                    used: 0,
+                    in_flight: 0,
+                };
+                (path, share)
+            })
+            .collect();
+        Quotas {
+            default,
+            state: Arc::new(Mutex::new(State {
+                shares,
+                done: Some(done),
+            })),
+        }
+    }
+
+    /// Whether `path` has a limit and no downloads left to give out.
+    pub fn exhausted(&self, path: &Path) -> bool {
+        match self.state.lock() {
+            Ok(state) => state
+                .shares
+                .get(path)
+                .is_some_and(|share| share.used + share.in_flight >= share.limit),
+            Err(_) => true,
+        }
+    }
+
+    /// Holds one download of `path` for the duration of a request.
+    pub fn reserve(&self, path: &Path) -> Reserve {
+        let Ok(mut state) = self.state.lock() else {
+            return Reserve::Exhausted;
+        };
+        let share = match (state.shares.contains_key(path), self.default) {
+            (false, None) => return Reserve::Unlimited,
+            (false, Some(limit)) => state.shares.entry(path.to_path_buf()).or_insert(Share {
+                limit,
+                used: 0,
+                in_flight: 0,
+            }),
+            (true, _) => match state.shares.get_mut(path) {
+                Some(share) => share,
+                None => return Reserve::Unlimited,
+            },
+        };
+        if share.used + share.in_flight >= share.limit {
+            return Reserve::Exhausted;
+        }
+        share.in_flight += 1;
+        Reserve::Granted(Reservation {
+            path: path.to_path_buf(),
+            state: Arc::clone(&self.state),
+            committed: false,
+        })
+    }
+}
+
+pub enum Reserve {
+    Unlimited,
+    Granted(Reservation),
+    Exhausted,
+}
+
+pub struct Reservation {
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+    committed: bool,
+}
+
+impl Reservation {
+    /// Counts the download as used.
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        if let Some(share) = state.shares.get_mut(&self.path) {
+            share.in_flight -= 1;
+            if self.committed {
+                share.used += 1;
+            }
+        }
+        if self.committed && state.shares.values().all(|share| share.used >= share.limit)
+            && let Some(done) = state.done.take()
+        {
+            let _ = done.send(());
+        }
+    }
+}
@@ -0,0 +1,385 @@
+use crate::conditional::{Validators, strong_match};
+use crate::http::{parse_http_date, unix_secs};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Beyond this many disjoint ranges we just send the whole file.
+const MAX_RANGES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Selection {
+    Full,
+    Partial(Vec<ByteRange>),
+    Unsatisfiable,
+}
+
+pub fn select(
+    range: Option<&str>,
+    if_range: Option<&str>,
+    len: u64,
+    validators: &Validators,
+) -> Selection {
+    let Some(range) = range else {
+        return Selection::Full;
+    };
+    if let Some(if_range) = if_range
+        && !if_range_matches(if_range, validators)
+    {
+        return Selection::Full;
+    }
+    parse(range, len)
+}
+
+fn if_range_matches(value: &str, validators: &Validators) -> bool {
+    let value = value.trim();
+    if value.starts_with('"') || value.starts_with("W/") {
+        return strong_match(value, &validators.etag);
+    }
+    match (parse_http_date(value), validators.modified) {
+        (Some(date), Some(modified)) => unix_secs(date) == unix_secs(modified),
+        _ => false,
+    }
+}
+
+pub fn parse(value: &str, len: u64) -> Selection {
+    let Some((unit, specs)) = value.trim().split_once('=') else {
+        return Selection::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Selection::Full;
+    }
+
+    let mut saw_spec = false;
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        let Some((first, last)) = spec.split_once('-') else {
+            return Selection::Full;
+        };
+        saw_spec = true;
+
+        let (first, last) = (first.trim(), last.trim());
+        if first.is_empty() {
+            let Some(suffix) = parse_pos(last) else {
+                return Selection::Full;
+            };
+            if suffix == 0 || len == 0 {
+                continue;
+            }
+            ranges.push(ByteRange {
+                start: len.saturating_sub(suffix),
+                end: len - 1,
+            });
+            continue;
+        }
+
+        let Some(start) = parse_pos(first) else {
+            return Selection::Full;
+        };
+        let end = if last.is_empty() {
+            u64::MAX
+        } else {
+            match parse_pos(last) {
+                Some(end) if end >= start => end,
+                _ => return Selection::Full,
+            }
+        };
+        if start >= len {
+            continue;
+        }
+        ranges.push(ByteRange {
+            start,
+            end: end.min(len - 1),
+        });
+    }
+
+    if !saw_spec {
+        return Selection::Full;
+    }
+    if ranges.is_empty() {
+        return Selection::Unsatisfiable;
+    }
+
+    let ranges = coalesce(ranges);
+    if ranges.len() > MAX_RANGES {
+        return Selection::Full;
+    }
+    Selection::Partial(ranges)
+}
+
+fn parse_pos(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Reads exactly `len` bytes of `file` starting at `start`, failing instead
+/// of coming up short if the file shrinks underneath us.
+pub struct FileSlice {
+    file: File,
+    start: u64,
+    remaining: u64,
+    positioned: bool,
+}
+
+impl FileSlice {
+    pub fn new(file: File, start: u64, len: u64) -> Self {
+        FileSlice {
+            file,
+            start,
+            remaining: len,
+            positioned: false,
+        }
+    }
+}
+
+impl Read for FileSlice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.positioned {
+            self.file.seek(SeekFrom::Start(self.start))?;
+            self.positioned = true;
+        }
+        let max = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = self.file.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file shrank while being served",
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+pub struct Concat {
+    parts: VecDeque<Box<dyn Read + Send>>,
+}
+
+impl Read for Concat {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(part) = self.parts.front_mut() {
+            let n = part.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.parts.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+pub struct Multipart {
+    pub boundary: String,
+    pub body: Concat,
+    pub len: u64,
+}
+
+pub fn multipart(
+    file: &File,
+    ranges: &[ByteRange],
+    total: u64,
+    content_type: &str,
+) -> io::Result<Multipart> {
+    let boundary = boundary();
+    let mut parts: VecDeque<Box<dyn Read + Send>> = VecDeque::new();
+    let mut len = 0u64;
+
+    for range in ranges {
+        let head = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            boundary,
+            content_type,
+            range.content_range(total)
+        );
+        len += head.len() as u64 + range.len();
+        parts.push_back(Box::new(Cursor::new(head.into_bytes())));
+        parts.push_back(Box::new(FileSlice::new(
+            file.try_clone()?,
+            range.start,
+            range.len(),
+        )));
+    }
+    let tail = format!("\r\n--{}--\r\n", boundary);
+    len += tail.len() as u64;
+    parts.push_back(Box::new(Cursor::new(tail.into_bytes())));
+
+    Ok(Multipart {
+        boundary,
+        body: Concat { parts },
+        len,
+    })
+}
+
+fn boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("nsv-{:x}-{:x}", nanos, std::process::id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::http_date;
+    use std::io::Write;
+    use std::time::Duration;
+
+    fn partial(ranges: &[(u64, u64)]) -> Selection {
+        Selection::Partial(
+            ranges
+                .iter()
+                .map(|&(start, end)| ByteRange { start, end })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(parse("bytes=0-9", 100), partial(&[(0, 9)]));
+        assert_eq!(parse("bytes=90-200", 100), partial(&[(90, 99)]));
+        assert_eq!(parse(" Bytes = 5-5 ", 100), partial(&[(5, 5)]));
+    }
+
+    #[test]
+    fn open_ended_ranges() {
+        assert_eq!(parse("bytes=10-", 100), partial(&[(10, 99)]));
+        assert_eq!(parse("bytes=0-", 1), partial(&[(0, 0)]));
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(parse("bytes=-10", 100), partial(&[(90, 99)]));
+        assert_eq!(parse("bytes=-500", 100), partial(&[(0, 99)]));
+        assert_eq!(parse("bytes=-0", 100), Selection::Unsatisfiable);
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_are_coalesced() {
+        assert_eq!(parse("bytes=0-9,5-19", 100), partial(&[(0, 19)]));
+        assert_eq!(parse("bytes=10-19,0-9", 100), partial(&[(0, 19)]));
+        assert_eq!(parse("bytes=0-9,-10", 100), partial(&[(0, 9), (90, 99)]));
+        assert_eq!(parse("bytes=50-,-60", 100), partial(&[(40, 99)]));
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        assert_eq!(parse("bytes=100-", 100), Selection::Unsatisfiable);
+        assert_eq!(parse("bytes=200-300", 100), Selection::Unsatisfiable);
+        assert_eq!(parse("bytes=100-,0-0", 100), partial(&[(0, 0)]));
+    }
+
+    #[test]
+    fn empty_files_cannot_satisfy_any_range() {
+        assert_eq!(parse("bytes=0-", 0), Selection::Unsatisfiable);
+        assert_eq!(parse("bytes=-5", 0), Selection::Unsatisfiable);
+    }
+
+    #[test]
+    fn invalid_specs_fall_back_to_the_full_file() {
+        for value in [
+            "bytes",
+            "items=0-9",
+            "bytes=",
+            "bytes=abc",
+            "bytes=9-0",
+            "bytes=-",
+            "bytes=+1-2",
+            "bytes=0-9,x",
+        ] {
+            assert_eq!(parse(value, 100), Selection::Full, "{value}");
+        }
+    }
+
+    #[test]
+    fn too_many_ranges_fall_back_to_the_full_file() {
+        let specs: Vec<String> = (0..=MAX_RANGES as u64)
+            .map(|i| format!("{}-{}", i * 2, i * 2))
+            .collect();
+        assert_eq!(parse(&format!("bytes={}", specs.join(",")), 1000), Selection::Full);
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_etag_or_the_exact_date() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let validators = Validators {
+            etag: "\"abc\"".to_string(),
+            modified: Some(modified),
+        };
+        assert!(if_range_matches("\"abc\"", &validators));
+        assert!(!if_range_matches("\"abd\"", &validators));
+        assert!(!if_range_matches("W/\"abc\"", &validators));
+        assert!(if_range_matches(&http_date(modified), &validators));
+        assert!(!if_range_matches(&http_date(modified + Duration::from_secs(1)), &validators));
+        assert!(!if_range_matches("yesterday", &validators));
+
+        assert_eq!(select(Some("bytes=0-0"), Some("\"old\""), 10, &validators), Selection::Full);
+        assert_eq!(select(Some("bytes=0-0"), Some("\"abc\""), 10, &validators), partial(&[(0, 0)]));
+    }
+
+    #[test]
+    fn file_slice_fails_when_the_file_shrinks() {
+        let path = std::env::temp_dir().join(format!("nsv-slice-{}", std::process::id()));
+        File::create(&path).unwrap().write_all(&[7u8; 100]).unwrap();
+        let file = File::open(&path).unwrap();
+        std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(40).unwrap();
+
+        let mut out = Vec::new();
+        let error = FileSlice::new(file, 10, 50).read_to_end(&mut out).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(out.len(), 30);
+    }
+
+    #[test]
+    fn file_slice_reads_exactly_its_range() {
+        let path = std::env::temp_dir().join(format!("nsv-slice-ok-{}", std::process::id()));
+        let content: Vec<u8> = (0..100u8).collect();
+        File::create(&path).unwrap().write_all(&content).unwrap();
+
+        let mut out = Vec::new();
+        FileSlice::new(File::open(&path).unwrap(), 10, 5).read_to_end(&mut out).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(out, &content[10..15]);
+    }
+}
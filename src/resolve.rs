@@ -0,0 +1,876 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// Which symlinks below the root may be followed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Symlinks {
+    /// Wherever they lead.
+    Follow,
+    /// As long as they stay below the root.
+    #[default]
+    Inside,
+    /// Not at all, in any component of the path.
+    Deny,
+}
+
+impl FromStr for Symlinks {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "follow" => Ok(Symlinks::Follow),
+            "inside" => Ok(Symlinks::Inside),
+            "deny" => Ok(Symlinks::Deny),
+            _ => Err(format!("symlinks must be follow, inside or deny, got {value}")),
+        }
+    }
+}
+
+/// The served directory, held open for the whole run so that request paths
+/// are resolved against it rather than against whatever its name points to
+/// by then.
+pub struct Root {
+    path: PathBuf,
+    symlinks: Symlinks,
+    /// Set with --one-file-system: files must be on this device and mount.
+    mount: Option<(u64, u64)>,
+    #[cfg(unix)]
+    dir: File,
+    #[cfg(target_os = "linux")]
+    no_openat2: std::sync::atomic::AtomicBool,
+}
+
+/// A file opened below the root, with where it really lives.
+pub struct Opened {
+    pub file: File,
+    pub path: PathBuf,
+}
+
+/// A directory opened below the root. Files are created, linked and removed
+/// relative to the open descriptor, so renaming the directory or a parent
+/// to a symlink in the meantime does not move where they end up.
+pub struct Dir {
+    file: File,
+    pub path: PathBuf,
+}
+
+#[derive(Debug)]
+enum Refused {
+    Outside,
+    Symlink,
+    OtherDevice,
+    Special,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Refused::Outside => "path leads outside the served directory",
+            Refused::Symlink => "path goes through a symlink",
+            Refused::OtherDevice => "file is on another filesystem",
+            Refused::Special => "not a regular file",
+        })
+    }
+}
+
+impl std::error::Error for Refused {}
+
+// Paths the symlink and filesystem policies rule out look just like missing
+// files, so that nothing gives away what is on the other side. Things that
+// are there but are not regular files are plainly forbidden.
+fn refused(reason: Refused) -> io::Error {
+    let kind = match reason {
+        Refused::Outside | Refused::Symlink | Refused::OtherDevice => io::ErrorKind::NotFound,
+        Refused::Special => io::ErrorKind::PermissionDenied,
+    };
+    io::Error::new(kind, reason)
+}
+
+/// Whether `error` is one of the refusals above rather than a failure.
+pub fn is_refusal(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<Refused>())
+}
+
+/// Opens `path` for reading without blocking on FIFOs, refusing anything
+/// but a regular file.
+pub fn open_file(path: &Path) -> io::Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(unix::FILE_FLAGS);
+    }
+    let file = options.open(path).map_err(classify)?;
+    if !file.metadata()?.is_file() {
+        return Err(refused(Refused::Special));
+    }
+    Ok(file)
+}
+
+// Opening a socket fails with ENXIO rather than handing back something to
+// fstat. ELOOP means a symlink that could not be followed, whether a loop,
+// a link where none are allowed or one swapped in behind the walk's back.
+fn classify(error: io::Error) -> io::Error {
+    #[cfg(unix)]
+    match error.raw_os_error() {
+        Some(libc::ENXIO) => return refused(Refused::Special),
+        Some(libc::ELOOP) => return refused(Refused::Symlink),
+        _ => {}
+    }
+    error
+}
+
+impl Root {
+    /// `path` must already be canonical. With `one_file_system`, only files
+    /// on the same filesystem as `path` are opened.
+    pub fn new(path: PathBuf, symlinks: Symlinks, one_file_system: bool) -> io::Result<Self> {
+        #[cfg(unix)]
+        let dir = unix::open_dir(&path)?;
+        let mount = if one_file_system {
+            #[cfg(unix)]
+            {
+                Some(unix::mount_of(&dir)?)
+            }
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "--one-file-system is only supported on unix",
+            ));
+        } else {
+            None
+        };
+        Ok(Root {
+            symlinks,
+            mount,
+            #[cfg(unix)]
+            dir,
+            #[cfg(target_os = "linux")]
+            no_openat2: std::sync::atomic::AtomicBool::new(false),
+            path,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `path` relative to the root, `/`-separated.
+    pub fn relative(&self, path: &Path) -> Option<String> {
+        let rel = path.strip_prefix(&self.path).ok()?;
+        Some(rel.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/"))
+    }
+
+    /// Opens the regular file at `rel` for reading. Unless symlinks may be
+    /// followed anywhere, this never leaves the root, whatever the symlinks
+    /// along the way are changed to in the meantime. FIFOs open without
+    /// waiting for a writer.
+    pub fn open(&self, rel: &str) -> io::Result<Opened> {
+        let opened = self.resolve(rel, false)?;
+        // Directories, FIFOs and device nodes alike; checked on the opened
+        // descriptor so the answer is about what would be sent.
+        if !opened.file.metadata()?.is_file() {
+            return Err(refused(Refused::Special));
+        }
+        Ok(opened)
+    }
+
+    /// Opens the directory at `rel`, or the root itself for an empty `rel`,
+    /// under the same rules as [`Root::open`].
+    pub fn open_dir(&self, rel: &str) -> io::Result<Dir> {
+        let rel = if rel.is_empty() { "." } else { rel };
+        let opened = self.resolve(rel, true)?;
+        if !opened.file.metadata()?.is_dir() {
+            return Err(refused(Refused::Special));
+        }
+        Ok(Dir {
+            file: opened.file,
+            path: opened.path,
+        })
+    }
+
+    fn resolve(&self, rel: &str, dir: bool) -> io::Result<Opened> {
+        let opened = match self.symlinks {
+            Symlinks::Follow => self.open_following(rel, dir),
+            Symlinks::Inside => self.open_beneath(rel, true, dir),
+            Symlinks::Deny => self.open_beneath(rel, false, dir),
+        };
+        let opened = opened.map_err(classify)?;
+        #[cfg(unix)]
+        if let Some(mount) = self.mount
+            && unix::mount_of(&opened.file)? != mount
+        {
+            return Err(refused(Refused::OtherDevice));
+        }
+        Ok(opened)
+    }
+
+    /// Only `..` in `rel` itself is kept from climbing out; links go
+    /// wherever they point.
+    fn open_following(&self, rel: &str, dir: bool) -> io::Result<Opened> {
+        let mut parts = Vec::new();
+        for component in Path::new(rel).components() {
+            match component {
+                Component::Normal(name) => parts.push(name),
+                Component::ParentDir => {
+                    parts.pop().ok_or_else(|| refused(Refused::Outside))?;
+                }
+                _ => {}
+            }
+        }
+        let path: PathBuf = parts.iter().collect();
+        let path = self.path.join(path);
+        let file = if dir { open_directory(&path)? } else { open_file(&path)? };
+        let path = path.canonicalize().unwrap_or(path);
+        Ok(Opened { file, path })
+    }
+
+    fn open_beneath(&self, rel: &str, links: bool, dir: bool) -> io::Result<Opened> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::sync::atomic::Ordering;
+
+            if !self.no_openat2.load(Ordering::Relaxed) {
+                match unix::openat2(&self.dir, rel, links, dir, self.mount.is_some()) {
+                    Ok(Some(opened)) => return Ok(opened),
+                    // Absolute links and mount crossings are refused outright
+                    // by the kernel; the walk below still follows links that
+                    // stay inside, and tells which it was.
+                    Ok(None) => {}
+                    Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EPERM)) => {
+                        self.no_openat2.store(true, Ordering::Relaxed);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        #[cfg(unix)]
+        {
+            unix::walk(&self.dir, &self.path, rel, links, dir, self.mount)
+        }
+        #[cfg(not(unix))]
+        {
+            if !links {
+                let mut path = self.path.clone();
+                for component in Path::new(rel).components() {
+                    path.push(component);
+                    if path.symlink_metadata().is_ok_and(|meta| meta.is_symlink()) {
+                        return Err(refused(Refused::Symlink));
+                    }
+                }
+            }
+            let path = self.path.join(rel).canonicalize()?;
+            if !path.starts_with(&self.path) {
+                return Err(refused(Refused::Outside));
+            }
+            let file = if dir { open_directory(&path)? } else { open_file(&path)? };
+            Ok(Opened { file, path })
+        }
+    }
+}
+
+fn open_directory(path: &Path) -> io::Result<File> {
+    #[cfg(unix)]
+    {
+        unix::open_dir(path)
+    }
+    #[cfg(not(unix))]
+    {
+        File::open(path)
+    }
+}
+
+impl Dir {
+    /// Creates `name` for writing, failing if anything is already there.
+    pub fn create_new(&self, name: &str) -> io::Result<File> {
+        #[cfg(unix)]
+        {
+            unix::create_new(&self.file, &unix::c_name(name)?)
+        }
+        #[cfg(not(unix))]
+        {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.path.join(name))
+        }
+    }
+
+    /// Hard links `from` to `to`, both names in this directory.
+    pub fn hard_link(&self, from: &str, to: &str) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            unix::link(&self.file, &unix::c_name(from)?, &unix::c_name(to)?)
+        }
+        #[cfg(not(unix))]
+        {
+            std::fs::hard_link(self.path.join(from), self.path.join(to))
+        }
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            unix::rename(&self.file, &unix::c_name(from)?, &unix::c_name(to)?)
+        }
+        #[cfg(not(unix))]
+        {
+            std::fs::rename(self.path.join(from), self.path.join(to))
+        }
+    }
+
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            unix::unlink(&self.file, &unix::c_name(name)?)
+        }
+        #[cfg(not(unix))]
+        {
+            std::fs::remove_file(self.path.join(name))
+        }
+    }
+
+    /// Whether `name` exists, without following it if it is a symlink.
+    pub fn contains(&self, name: &str) -> bool {
+        #[cfg(unix)]
+        {
+            unix::c_name(name).is_ok_and(|name| unix::is_symlink(&self.file, &name).is_ok())
+        }
+        #[cfg(not(unix))]
+        {
+            self.path.join(name).symlink_metadata().is_ok()
+        }
+    }
+}
+
+/// Mount points strictly below `dir`, as far as the platform tells.
+pub fn mount_points_below(dir: &Path) -> Vec<PathBuf> {
+    // Fields are separated by spaces, the fifth is where it is mounted, with
+    // spaces and the like written as octal escapes.
+    let Ok(mountinfo) = std::fs::read_to_string("/proc/self/mountinfo") else {
+        return Vec::new();
+    };
+    let mut mounts: Vec<PathBuf> = mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|field| PathBuf::from(unescape_octal(field)))
+        .filter(|mount| mount != dir && mount.starts_with(dir))
+        .collect();
+    mounts.sort();
+    mounts.dedup();
+    mounts
+}
+
+fn unescape_octal(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let digits = bytes.get(i + 1..i + 4);
+        if bytes[i] == b'\\'
+            && let Some(digits) = digits
+            && digits.iter().all(|d| (b'0'..=b'7').contains(d))
+        {
+            out.push(digits.iter().fold(0u8, |n, d| n.wrapping_mul(8) + (d - b'0')));
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::{Opened, Refused, refused};
+    use std::collections::VecDeque;
+    use std::ffi::{CString, OsStr, OsString};
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::{Component, Path, PathBuf};
+
+    // Same limit the kernel applies.
+    const MAX_LINKS: u32 = 40;
+
+    // Directories along the way only need to be searchable.
+    #[cfg(target_os = "linux")]
+    const DIR_FLAGS: libc::c_int = libc::O_PATH | libc::O_DIRECTORY;
+    #[cfg(not(target_os = "linux"))]
+    const DIR_FLAGS: libc::c_int = libc::O_RDONLY | libc::O_DIRECTORY;
+
+    // Read-only flags for the file itself: a FIFO must not block the open,
+    // and a terminal must not become ours.
+    pub const FILE_FLAGS: libc::c_int = libc::O_NONBLOCK | libc::O_NOCTTY;
+
+    pub fn open_dir(path: &Path) -> io::Result<File> {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(DIR_FLAGS)
+            .open(path)
+    }
+
+    /// `None` when the kernel refused because of an absolute symlink or a
+    /// mount point, or the opened file's location cannot be read back.
+    #[cfg(target_os = "linux")]
+    pub fn openat2(
+        dir: &File,
+        rel: &str,
+        links: bool,
+        directory: bool,
+        same_mount: bool,
+    ) -> io::Result<Option<Opened>> {
+        let c_rel = CString::new(rel)?;
+        // SAFETY: open_how is plain data; zero is a valid value for every field.
+        let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+        let flags = if directory { DIR_FLAGS } else { libc::O_RDONLY | FILE_FLAGS };
+        how.flags = (flags | libc::O_CLOEXEC) as u64;
+        how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+        if !links {
+            how.resolve |= libc::RESOLVE_NO_SYMLINKS;
+        }
+        if same_mount {
+            how.resolve |= libc::RESOLVE_NO_XDEV;
+        }
+        let fd = loop {
+            // SAFETY: the path and open_how outlive the call, and the size
+            // passed is the size of the struct pointed to.
+            let fd = unsafe {
+                libc::syscall(
+                    libc::SYS_openat2,
+                    dir.as_raw_fd(),
+                    c_rel.as_ptr(),
+                    &how as *const libc::open_how,
+                    std::mem::size_of::<libc::open_how>(),
+                )
+            };
+            if fd >= 0 {
+                break fd as libc::c_int;
+            }
+            let error = io::Error::last_os_error();
+            match error.raw_os_error() {
+                // A rename elsewhere in the tree raced the lookup.
+                Some(libc::EAGAIN | libc::EINTR) => continue,
+                Some(libc::EXDEV) => return Ok(None),
+                _ => return Err(error),
+            }
+        };
+        // SAFETY: fd was just returned by the kernel and nothing else owns it.
+        let file = unsafe { File::from_raw_fd(fd) };
+        match std::fs::read_link(format!("/proc/self/fd/{fd}")) {
+            Ok(path) => Ok(Some(Opened { file, path })),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Resolves `rel` one component at a time with `O_NOFOLLOW`, reading
+    /// symlinks itself so that `..` and link targets can be kept inside
+    /// `root`. Without `links`, any symlink on the way is refused; with a
+    /// `mount`, so is every directory or file that is not on it.
+    pub fn walk(
+        root: &File,
+        root_path: &Path,
+        rel: &str,
+        links: bool,
+        directory: bool,
+        mount: Option<(u64, u64)>,
+    ) -> io::Result<Opened> {
+        let mut pending: VecDeque<OsString> = Path::new(rel)
+            .components()
+            .filter_map(component)
+            .collect();
+        let mut dirs: Vec<(File, PathBuf)> = Vec::new();
+        let mut followed = 0;
+        while let Some(name) = pending.pop_front() {
+            if name == ".." {
+                if dirs.pop().is_none() {
+                    return Err(refused(Refused::Outside));
+                }
+                continue;
+            }
+            let (parent, parent_path) = match dirs.last() {
+                Some((dir, path)) => (dir, path.as_path()),
+                None => (root, root_path),
+            };
+            let c_name = CString::new(name.as_bytes())?;
+            if is_symlink(parent, &c_name)? {
+                if !links {
+                    return Err(refused(Refused::Symlink));
+                }
+                followed += 1;
+                if followed > MAX_LINKS {
+                    return Err(refused(Refused::Symlink));
+                }
+                let target = PathBuf::from(read_link(parent, &c_name)?);
+                let target = if target.is_absolute() {
+                    dirs.clear();
+                    target
+                        .strip_prefix(root_path)
+                        .map_err(|_| refused(Refused::Outside))?
+                        .to_path_buf()
+                } else {
+                    target
+                };
+                for part in target.components().filter_map(component).rev() {
+                    pending.push_front(part);
+                }
+                continue;
+            }
+            let path = parent_path.join(&name);
+            let last = pending.is_empty();
+            // Should the name have turned into a link since the check above,
+            // O_NOFOLLOW makes this fail instead of following it.
+            let flags = if last && !directory { libc::O_RDONLY | FILE_FLAGS } else { DIR_FLAGS };
+            let file = openat(parent, &c_name, flags | libc::O_NOFOLLOW | libc::O_CLOEXEC)?;
+            if let Some(mount) = mount
+                && mount_of(&file)? != mount
+            {
+                return Err(refused(Refused::OtherDevice));
+            }
+            if last {
+                return Ok(Opened { file, path });
+            }
+            dirs.push((file, path));
+        }
+        // Only reached for paths ending in `..`, which always name a directory.
+        match dirs.pop() {
+            Some((dir, path)) => {
+                let file = openat(&dir, c".", libc::O_RDONLY | libc::O_CLOEXEC)?;
+                Ok(Opened { file, path })
+            }
+            None => {
+                let file = openat(root, c".", libc::O_RDONLY | libc::O_CLOEXEC)?;
+                Ok(Opened {
+                    file,
+                    path: root_path.to_path_buf(),
+                })
+            }
+        }
+    }
+
+    /// The filesystem `file` is on: its device and, where the kernel tells,
+    /// the mount it was reached through, which also sets bind mounts apart.
+    pub fn mount_of(file: &File) -> io::Result<(u64, u64)> {
+        use std::os::unix::fs::MetadataExt;
+        Ok((file.metadata()?.dev(), mount_id(file)))
+    }
+
+    #[cfg(all(target_os = "linux", any(target_env = "gnu", target_env = "musl")))]
+    fn mount_id(file: &File) -> u64 {
+        // SAFETY: statx is plain data, filled in by the call on success.
+        let mut stat: libc::statx = unsafe { std::mem::zeroed() };
+        // SAFETY: the empty path is NUL-terminated and stat is a valid out
+        // pointer; AT_EMPTY_PATH makes this about the descriptor itself.
+        let result = unsafe {
+            libc::statx(
+                file.as_raw_fd(),
+                c"".as_ptr(),
+                libc::AT_EMPTY_PATH,
+                libc::STATX_MNT_ID,
+                &mut stat,
+            )
+        };
+        if result == 0 && stat.stx_mask & libc::STATX_MNT_ID != 0 {
+            stat.stx_mnt_id
+        } else {
+            0
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", any(target_env = "gnu", target_env = "musl"))))]
+    fn mount_id(_file: &File) -> u64 {
+        0
+    }
+
+    fn component(component: Component<'_>) -> Option<OsString> {
+        match component {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsStr::new("..").to_os_string()),
+            _ => None,
+        }
+    }
+
+    fn openat(dir: &File, name: &std::ffi::CStr, flags: libc::c_int) -> io::Result<File> {
+        // SAFETY: name is NUL-terminated and dir stays open for the call.
+        let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd was just returned by the kernel and nothing else owns it.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    pub fn c_name(name: &str) -> io::Result<CString> {
+        Ok(CString::new(name)?)
+    }
+
+    pub fn create_new(dir: &File, name: &std::ffi::CStr) -> io::Result<File> {
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        // SAFETY: name is NUL-terminated and dir stays open for the call; the
+        // mode is passed as the variadic argument O_CREAT needs.
+        let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags, 0o666 as libc::c_uint) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd was just returned by the kernel and nothing else owns it.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    pub fn link(dir: &File, from: &std::ffi::CStr, to: &std::ffi::CStr) -> io::Result<()> {
+        let fd = dir.as_raw_fd();
+        // SAFETY: both names are NUL-terminated and dir stays open for the call.
+        check(unsafe { libc::linkat(fd, from.as_ptr(), fd, to.as_ptr(), 0) })
+    }
+
+    pub fn rename(dir: &File, from: &std::ffi::CStr, to: &std::ffi::CStr) -> io::Result<()> {
+        let fd = dir.as_raw_fd();
+        // SAFETY: both names are NUL-terminated and dir stays open for the call.
+        check(unsafe { libc::renameat(fd, from.as_ptr(), fd, to.as_ptr()) })
+    }
+
+    pub fn unlink(dir: &File, name: &std::ffi::CStr) -> io::Result<()> {
+        // SAFETY: name is NUL-terminated and dir stays open for the call.
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) })
+    }
+
+    fn check(result: libc::c_int) -> io::Result<()> {
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn is_symlink(dir: &File, name: &std::ffi::CStr) -> io::Result<bool> {
+        // SAFETY: stat is plain data, filled in by the call on success.
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        // SAFETY: name is NUL-terminated and stat is a valid out pointer.
+        let result = unsafe {
+            libc::fstatat(dir.as_raw_fd(), name.as_ptr(), &mut stat, libc::AT_SYMLINK_NOFOLLOW)
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(stat.st_mode & libc::S_IFMT == libc::S_IFLNK)
+    }
+
+    fn read_link(dir: &File, name: &std::ffi::CStr) -> io::Result<OsString> {
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        // SAFETY: buf is writable for its full length.
+        let len = unsafe {
+            libc::readlinkat(dir.as_raw_fd(), name.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(len as usize);
+        Ok(OsString::from_vec(buf))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("nsv-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir(&dir).unwrap();
+            TempDir(dir.canonicalize().unwrap())
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn mkfifo(path: &Path) {
+        let c_path = std::ffi::CString::new(path.as_os_str().as_encoded_bytes()).unwrap();
+        // SAFETY: c_path is NUL-terminated.
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) }, 0);
+    }
+
+    // Runs `open` on another thread so a blocking open fails the test
+    // instead of hanging it.
+    fn open_within(root: Root, rel: &'static str) -> io::Result<Opened> {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = sender.send(root.open(rel));
+        });
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("open blocked")
+    }
+
+    fn refusal(result: io::Result<Opened>) -> io::ErrorKind {
+        match result {
+            Ok(opened) => panic!("opened {}", opened.path.display()),
+            Err(e) => {
+                assert!(is_refusal(&e), "{e}");
+                e.kind()
+            }
+        }
+    }
+
+    #[test]
+    fn regular_files_open() {
+        let dir = TempDir::new("regular");
+        std::fs::create_dir(dir.0.join("sub")).unwrap();
+        std::fs::write(dir.0.join("sub/a.txt"), "a").unwrap();
+        symlink("sub/a.txt", dir.0.join("link")).unwrap();
+        let root = Root::new(dir.0.clone(), Symlinks::Inside, false).unwrap();
+        assert_eq!(root.open("sub/a.txt").unwrap().path, dir.0.join("sub/a.txt"));
+        assert_eq!(root.open("sub/../link").unwrap().path, dir.0.join("sub/a.txt"));
+    }
+
+    #[test]
+    fn fifos_open_without_blocking_and_are_refused() {
+        let dir = TempDir::new("fifo");
+        mkfifo(&dir.0.join("fifo"));
+        let root = Root::new(dir.0.clone(), Symlinks::Inside, false).unwrap();
+        assert_eq!(refusal(open_within(root, "fifo")), io::ErrorKind::PermissionDenied);
+        let error = open_file(&dir.0.join("fifo")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn sockets_are_refused() {
+        let dir = TempDir::new("socket");
+        let _listener = UnixListener::bind(dir.0.join("sock")).unwrap();
+        let root = Root::new(dir.0.clone(), Symlinks::Inside, false).unwrap();
+        assert_eq!(refusal(open_within(root, "sock")), io::ErrorKind::PermissionDenied);
+        let error = open_file(&dir.0.join("sock")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn device_nodes_are_refused() {
+        let dir = TempDir::new("device");
+        symlink("/dev/null", dir.0.join("null")).unwrap();
+        // Outside the root, so refused like a missing file first...
+        let root = Root::new(dir.0.clone(), Symlinks::Inside, false).unwrap();
+        assert_eq!(refusal(root.open("null")), io::ErrorKind::NotFound);
+        // ...and for being a device when links may go anywhere.
+        let root = Root::new(dir.0.clone(), Symlinks::Follow, false).unwrap();
+        assert_eq!(refusal(open_within(root, "null")), io::ErrorKind::PermissionDenied);
+        let error = open_file(Path::new("/dev/null")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn directories_are_refused() {
+        let dir = TempDir::new("dirs");
+        std::fs::create_dir(dir.0.join("sub")).unwrap();
+        let root = Root::new(dir.0.clone(), Symlinks::Inside, false).unwrap();
+        assert_eq!(refusal(root.open("sub")), io::ErrorKind::PermissionDenied);
+        assert_eq!(refusal(root.open("sub/..")), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn directories_open_only_inside_the_root() {
+        let dir = TempDir::new("open-dir");
+        let outside = TempDir::new("open-dir-outside");
+        std::fs::create_dir(dir.0.join("sub")).unwrap();
+        std::fs::write(dir.0.join("file"), "f").unwrap();
+        symlink(&outside.0, dir.0.join("out")).unwrap();
+        symlink("sub", dir.0.join("in")).unwrap();
+        let root = Root::new(dir.0.clone(), Symlinks::Inside, false).unwrap();
+        let refused = |rel| match root.open_dir(rel) {
+            Ok(opened) => panic!("opened {}", opened.path.display()),
+            Err(e) => {
+                assert!(is_refusal(&e), "{e}");
+                e.kind()
+            }
+        };
+
+        assert_eq!(root.open_dir("").unwrap().path, dir.0);
+        assert_eq!(root.open_dir("in").unwrap().path, dir.0.join("sub"));
+        assert_eq!(refused("out"), io::ErrorKind::NotFound);
+        assert_eq!(refused(".."), io::ErrorKind::NotFound);
+        let error = root.open_dir("file").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotADirectory);
+    }
+
+    #[test]
+    fn files_are_created_in_the_opened_directory() {
+        let dir = TempDir::new("dir-ops");
+        let outside = TempDir::new("dir-ops-outside");
+        std::fs::create_dir(dir.0.join("sub")).unwrap();
+        let root = Root::new(dir.0.clone(), Symlinks::Inside, false).unwrap();
+        let sub = root.open_dir("sub").unwrap();
+
+        sub.create_new("a").unwrap();
+        assert_eq!(sub.create_new("a").unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        sub.hard_link("a", "b").unwrap();
+        assert_eq!(sub.hard_link("a", "b").unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        sub.remove("a").unwrap();
+        assert!(!sub.contains("a") && sub.contains("b"));
+
+        // Swapping the directory for a link out of the root afterwards does
+        // not redirect anything.
+        std::fs::rename(dir.0.join("sub"), dir.0.join("moved")).unwrap();
+        symlink(&outside.0, dir.0.join("sub")).unwrap();
+        sub.create_new("c").unwrap();
+        sub.rename("c", "d").unwrap();
+        assert!(dir.0.join("moved/d").exists());
+        assert!(!outside.0.join("c").exists() && !outside.0.join("d").exists());
+    }
+
+    #[test]
+    fn symlink_loops_look_like_missing_files() {
+        let dir = TempDir::new("loop");
+        symlink("b", dir.0.join("a")).unwrap();
+        symlink("a", dir.0.join("b")).unwrap();
+        for symlinks in [Symlinks::Follow, Symlinks::Inside, Symlinks::Deny] {
+            let root = Root::new(dir.0.clone(), symlinks, false).unwrap();
+            assert_eq!(refusal(root.open("a")), io::ErrorKind::NotFound, "{symlinks:?}");
+        }
+        let root = unix::open_dir(&dir.0).unwrap();
+        assert_eq!(refusal(unix::walk(&root, &dir.0, "a", true, false, None)), io::ErrorKind::NotFound);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn one_file_system_stays_on_the_root_filesystem() {
+        let dir = TempDir::new("xdev");
+        std::fs::write(dir.0.join("a.txt"), "a").unwrap();
+        symlink("/proc/version", dir.0.join("version")).unwrap();
+
+        let root = Root::new(dir.0.clone(), Symlinks::Follow, true).unwrap();
+        assert!(root.open("a.txt").is_ok());
+        assert_eq!(refusal(root.open("version")), io::ErrorKind::NotFound);
+        let root = Root::new(dir.0.clone(), Symlinks::Follow, false).unwrap();
+        assert!(root.open("version").is_ok());
+
+        let dir_fd = unix::open_dir(&dir.0).unwrap();
+        let mount = Some(unix::mount_of(&dir_fd).unwrap());
+        assert!(unix::walk(&dir_fd, &dir.0, "a.txt", true, false, mount).is_ok());
+    }
+
+    #[test]
+    fn the_fallback_walk_refuses_special_files_too() {
+        let dir = TempDir::new("walk");
+        mkfifo(&dir.0.join("fifo"));
+        let root = unix::open_dir(&dir.0).unwrap();
+        // The walk itself opens the FIFO without blocking; Root::open then
+        // refuses it by type.
+        let opened = unix::walk(&root, &dir.0, "fifo", true, false, None).unwrap();
+        assert!(!opened.file.metadata().unwrap().is_file());
+    }
+}
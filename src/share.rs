@@ -0,0 +1,150 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use crate::resolve::{self, Opened};
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+
+struct Shared {
+    name: String,
+    path: PathBuf,
+    /// Device and inode at startup, to tell the file from a replacement.
+    id: (u64, u64),
+}
+
+/// Files picked on the command line, each reachable as `/<id>/<name>` and
+/// nothing else.
+pub struct Shares {
+    files: HashMap<String, Shared>,
+}
+
+impl Shares {
+    pub fn new(paths: &[PathBuf]) -> Result<Self, String> {
+        let mut files = HashMap::new();
+        let mut seen = Vec::new();
+        for path in paths {
+            let canonical = path
+                .canonicalize()
+                .map_err(|e| format!("Cannot share {}: {e}", path.display()))?;
+            let meta = canonical
+                .metadata()
+                .map_err(|e| format!("Cannot share {}: {e}", path.display()))?;
+            if !meta.is_file() {
+                return Err(format!("Cannot share {}: not a regular file", path.display()));
+            }
+            if seen.contains(&canonical) {
+                continue;
+            }
+            let name = canonical
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| format!("Cannot share {}: file name is not UTF-8", path.display()))?
+                .to_string();
+            let id = loop {
+                let id = short_id().map_err(|e| e.to_string())?;
+                if !files.contains_key(&id) {
+                    break id;
+                }
+            };
+            seen.push(canonical.clone());
+            files.insert(
+                id,
+                Shared {
+                    name,
+                    path: canonical,
+                    id: file_id(&meta),
+                },
+            );
+        }
+        Ok(Shares { files })
+    }
+
+    /// Opens the file behind a decoded `<id>/<name>` request path, `None`
+    /// if nothing is shared under it. Whatever the path leads to by now is
+    /// opened and then compared with the file that was shared, so a link or
+    /// another file swapped in looks like a missing one.
+    pub fn open(&self, rel: &str) -> Option<io::Result<Opened>> {
+        let (id, name) = rel.split_once('/')?;
+        let shared = self.files.get(id).filter(|shared| shared.name == name)?;
+        let opened = resolve::open_file(&shared.path).and_then(|file| {
+            if file_id(&file.metadata()?) != shared.id {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "shared file was replaced"));
+            }
+            Ok(Opened {
+                file,
+                path: shared.path.clone(),
+            })
+        });
+        Some(opened)
+    }
+
+    /// `<id>/<name>` for every shared file, with its location on disk.
+    pub fn links(&self) -> Vec<(String, &Path)> {
+        let mut links: Vec<_> = self
+            .files
+            .iter()
+            .map(|(id, shared)| (format!("{id}/{}", shared.name), shared.path.as_path()))
+            .collect();
+        links.sort_by(|a, b| a.1.cmp(b.1));
+        links
+    }
+}
+
+#[cfg(unix)]
+fn file_id(meta: &Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (meta.dev(), meta.ino())
+}
+
+// Without inode numbers, size and modification time are the next best thing.
+#[cfg(not(unix))]
+fn file_id(meta: &Metadata) -> (u64, u64) {
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos() as u64);
+    (meta.len(), modified)
+}
+
+fn short_id() -> io::Result<String> {
+    let mut id = [0u8; 6];
+    getrandom::fill(&mut id)?;
+    Ok(URL_SAFE_NO_PAD.encode(id))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn replaced_files_are_not_served() {
+        let dir = std::env::temp_dir().join(format!("nsv-share-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        fs::write(dir.join("other.txt"), "other").unwrap();
+        let shares = Shares::new(&[dir.join("a.txt")]).unwrap();
+        let (rel, _) = shares.links().remove(0);
+
+        let opened = shares.open(&rel).unwrap().unwrap();
+        assert_eq!(opened.path, dir.join("a.txt"));
+        assert!(shares.open("nope/a.txt").is_none());
+        assert!(shares.open(&rel.replace("a.txt", "other.txt")).is_none());
+
+        // Kept around so its inode number cannot be handed out again.
+        fs::rename(dir.join("a.txt"), dir.join("kept.txt")).unwrap();
+        std::os::unix::fs::symlink(dir.join("other.txt"), dir.join("a.txt")).unwrap();
+        let swapped = shares.open(&rel).unwrap().err().map(|e| e.kind());
+
+        fs::remove_file(dir.join("a.txt")).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        let rewritten = shares.open(&rel).unwrap().err().map(|e| e.kind());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(swapped, Some(io::ErrorKind::NotFound));
+        assert_eq!(rewritten, Some(io::ErrorKind::NotFound));
+    }
+}
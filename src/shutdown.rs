@@ -0,0 +1,94 @@
+use crate::units::{Interval, human_bytes};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
+
+/// Sends one message per SIGINT or SIGTERM (Ctrl-C elsewhere) to `sender`.
+pub fn forward_signals(sender: Sender<()>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    #[cfg(unix)]
+    {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        use signal_hook::iterator::Signals;
+
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                if sender.send(()).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    ctrlc::set_handler(move || {
+        let _ = sender.send(());
+    })?;
+
+    Ok(())
+}
+
+/// Running totals for the summary printed on the way out.
+pub struct Stats {
+    started: Instant,
+    pub stopping: AtomicBool,
+    requests: AtomicU64,
+    downloads: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            started: Instant::now(),
+            stopping: AtomicBool::new(false),
+            requests: AtomicU64::new(0),
+            downloads: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, bytes: u64, complete_download: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if complete_download {
+            self.downloads.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn summary(&self, unfinished: usize) -> String {
+        let uptime = Duration::from_secs(self.started.elapsed().as_secs());
+        let mut summary = format!(
+            "Served {} requests ({} complete downloads, {}) in {}.",
+            self.requests.load(Ordering::Relaxed),
+            self.downloads.load(Ordering::Relaxed),
+            human_bytes(self.bytes.load(Ordering::Relaxed)),
+            Interval(uptime)
+        );
+        if unfinished > 0 {
+            summary.push_str(&format!(" {unfinished} requests were cut off."));
+        }
+        summary
+    }
+}
+
+pub enum Drained {
+    Idle,
+    TimedOut,
+    Forced,
+}
+
+/// Waits for `pending` to reach zero, giving up after `timeout` or on
+/// another signal.
+pub fn drain(pending: impl Fn() -> usize, timeout: Duration, signals: &Receiver<()>) -> Drained {
+    let deadline = Instant::now() + timeout;
+    while pending() > 0 {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Drained::TimedOut;
+        }
+        if signals.recv_timeout(left.min(Duration::from_millis(100))).is_ok() {
+            return Drained::Forced;
+        }
+    }
+    Drained::Idle
+}
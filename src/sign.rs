@@ -0,0 +1,131 @@
+use crate::capability::hex;
+use crate::http::encode_path;
+use crate::flag_value;
+use crate::units::parse_duration;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub enum Verdict {
+    Valid,
+    Invalid,
+    Expired,
+}
+
+/// Signs and verifies `?exp=...&sig=...` download links.
+pub struct Signer {
+    key: Vec<u8>,
+}
+
+impl Signer {
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let key = fs::read(path).map_err(|e| format!("Cannot read {}: {e}", path.display()))?;
+        let key = key.trim_ascii().to_vec();
+        if key.len() < 16 {
+            return Err(format!("Key in {} must be at least 16 bytes", path.display()).into());
+        }
+        Ok(Signer { key })
+    }
+
+    fn mac(&self, rel: &str, exp: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key size");
+        mac.update(rel.as_bytes());
+        mac.update(b"\n");
+        mac.update(exp.to_string().as_bytes());
+        mac
+    }
+
+    pub fn sign(&self, rel: &str, exp: u64) -> String {
+        hex(&self.mac(rel, exp).finalize().into_bytes())
+    }
+
+    pub fn verify(&self, rel: &str, query: &str) -> Verdict {
+        let mut exp = None;
+        let mut sig = None;
+        for pair in query.split('&') {
+            match pair.split_once('=') {
+                Some(("exp", value)) => exp = value.parse::<u64>().ok(),
+                Some(("sig", value)) => sig = unhex(value),
+                _ => {}
+            }
+        }
+        let (Some(exp), Some(sig)) = (exp, sig) else {
+            return Verdict::Invalid;
+        };
+        if self.mac(rel, exp).verify_slice(&sig).is_err() {
+            return Verdict::Invalid;
+        }
+        if exp < now() {
+            return Verdict::Expired;
+        }
+        Verdict::Valid
+    }
+}
+
+fn unhex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// `nsv sign <path> --expires 2h --sign-key nsv.key`
+pub fn run(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut path: Option<PathBuf> = None;
+    let mut expires = Duration::from_secs(3600);
+    let mut key_file: Option<PathBuf> = None;
+
+    while let Some(arg) = args.next() {
+        if arg == "--expires" {
+            let value = flag_value(&mut args, &arg)?;
+            expires = parse_duration(&value).ok_or("--expires takes a duration like 90s, 30m, 2h or 7d")?;
+            continue;
+        }
+        if arg == "--sign-key" {
+            key_file = Some(flag_value(&mut args, &arg)?.into());
+            continue;
+        }
+        if arg.starts_with('-') {
+            return Err(format!("Unknown flag: {arg}").into());
+        }
+        if path.is_some() {
+            return Err("sign takes exactly one path".into());
+        }
+        path = Some(arg.into());
+    }
+
+    let path = path.ok_or("Usage: nsv sign <path> --sign-key <file> [--expires 2h]")?;
+    let key_file = key_file.ok_or("sign needs --sign-key <file>")?;
+    let signer = Signer::from_file(&key_file)?;
+
+    let base_dir = env::current_dir()?.canonicalize()?;
+    let file = path
+        .canonicalize()
+        .map_err(|e| format!("Cannot sign {}: {e}", path.display()))?;
+    let rel = file
+        .strip_prefix(&base_dir)
+        .ok()
+        .and_then(|rel| rel.to_str())
+        .ok_or_else(|| format!("{} is not below {}", path.display(), base_dir.display()))?
+        .replace(std::path::MAIN_SEPARATOR, "/");
+
+    let exp = now() + expires.as_secs();
+    println!("{}?exp={}&sig={}", encode_path(&rel), exp, signer.sign(&rel, exp));
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How far ahead of its rate a transfer may get after a pause.
+const BURST: Duration = Duration::from_millis(250);
+const MIN_BURST_BYTES: f64 = 16.0 * 1024.0;
+
+/// Egress limits in bytes per second, 0 meaning unlimited. Both can be
+/// changed while transfers are running.
+#[derive(Default)]
+pub struct Throttle {
+    per_connection: AtomicU64,
+    global: AtomicU64,
+    active: AtomicUsize,
+}
+
+impl Throttle {
+    pub fn set(&self, per_connection: u64, global: u64) {
+        self.per_connection.store(per_connection, Ordering::Relaxed);
+        self.global.store(global, Ordering::Relaxed);
+    }
+
+    /// The rate one transfer may use right now: its own cap, or an equal
+    /// share of the global cap, whichever is lower.
+    fn rate(&self) -> u64 {
+        let per_connection = self.per_connection.load(Ordering::Relaxed);
+        let global = self.global.load(Ordering::Relaxed);
+        let share = match global {
+            0 => 0,
+            global => (global / self.active.load(Ordering::Relaxed).max(1) as u64).max(1),
+        };
+        match (per_connection, share) {
+            (0, rate) | (rate, 0) => rate,
+            (a, b) => a.min(b),
+        }
+    }
+}
+
+/// A response body paced by a [`Throttle`]. It counts as an active transfer
+/// from creation until it is dropped.
+pub struct Throttled<R> {
+    inner: R,
+    throttle: Arc<Throttle>,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl<R> Throttled<R> {
+    pub fn new(inner: R, throttle: Arc<Throttle>) -> Self {
+        throttle.active.fetch_add(1, Ordering::Relaxed);
+        Throttled {
+            inner,
+            throttle,
+            tokens: 0.0,
+            updated: Instant::now(),
+        }
+    }
+}
+
+impl<R> Drop for Throttled<R> {
+    fn drop(&mut self) {
+        self.throttle.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let rate = self.throttle.rate() as f64;
+            if rate == 0.0 {
+                self.updated = Instant::now();
+                return self.inner.read(buf);
+            }
+
+            let now = Instant::now();
+            let burst = (rate * BURST.as_secs_f64()).max(MIN_BURST_BYTES);
+            self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * rate).min(burst);
+            self.updated = now;
+            // Aim for chunks of about 50ms worth rather than single bytes.
+            let want = (buf.len() as f64).min(burst).min((rate / 20.0).max(1.0));
+            if self.tokens >= want {
+                let allowed = buf.len().min(self.tokens as usize);
+                let n = self.inner.read(&mut buf[..allowed])?;
+                self.tokens -= n as f64;
+                return Ok(n);
+            }
+            // Sleep in short steps so a raised limit takes effect quickly.
+            let wait = ((want - self.tokens) / rate).min(0.1);
+            thread::sleep(Duration::from_secs_f64(wait.max(0.001)));
+        }
+    }
+}
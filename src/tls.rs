@@ -0,0 +1,53 @@
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use tiny_http::SslConfig;
+
+pub struct Tls {
+    pub config: SslConfig,
+    pub fingerprint: String,
+}
+
+pub fn load(cert: &Path, key: &Path) -> Result<Tls, Box<dyn Error + Send + Sync>> {
+    let certificate =
+        fs::read(cert).map_err(|e| format!("Cannot read {}: {e}", cert.display()))?;
+    let private_key = fs::read(key).map_err(|e| format!("Cannot read {}: {e}", key.display()))?;
+
+    let leaf = rustls_pemfile::certs(&mut certificate.as_slice())
+        .next()
+        .ok_or_else(|| format!("No certificate found in {}", cert.display()))??;
+
+    Ok(Tls {
+        fingerprint: fingerprint(&leaf),
+        config: SslConfig {
+            certificate,
+            private_key,
+        },
+    })
+}
+
+pub fn self_signed() -> Result<Tls, Box<dyn Error + Send + Sync>> {
+    let names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+    let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(names)?;
+
+    Ok(Tls {
+        fingerprint: fingerprint(cert.der()),
+        config: SslConfig {
+            certificate: cert.pem().into_bytes(),
+            private_key: key_pair.serialize_pem().into_bytes(),
+        },
+    })
+}
+
+fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A byte count written as `512`, `64KiB`, `10MB`, `1.5GiB` and so on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let split = value
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(value.len());
+        let (amount, unit) = value.split_at(split);
+        let amount: f64 = amount
+            .parse()
+            .map_err(|_| format!("Invalid size: {value}"))?;
+        let scale: u64 = match unit.trim() {
+            "" | "B" => 1,
+            "K" | "KB" | "k" | "kB" => 1000,
+            "KiB" => 1 << 10,
+            "M" | "MB" => 1000 * 1000,
+            "MiB" => 1 << 20,
+            "G" | "GB" => 1000 * 1000 * 1000,
+            "GiB" => 1 << 30,
+            "T" | "TB" => 1000 * 1000 * 1000 * 1000,
+            "TiB" => 1 << 40,
+            _ => return Err(format!("Invalid size unit in {value}")),
+        };
+        Ok(ByteSize((amount * scale as f64) as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (unit, scale) in [("TiB", 1u64 << 40), ("GiB", 1 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10)] {
+            if self.0 >= scale && self.0.is_multiple_of(scale) {
+                return write!(f, "{}{}", self.0 / scale, unit);
+            }
+        }
+        write!(f, "{}", self.0)
+    }
+}
+
+impl TryFrom<String> for ByteSize {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ByteSize> for String {
+    fn from(size: ByteSize) -> String {
+        size.to_string()
+    }
+}
+
+/// A span of time written as `90`, `90s`, `30m`, `2h` or `7d`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Interval(pub Duration);
+
+impl FromStr for Interval {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        parse_duration(value)
+            .map(Interval)
+            .ok_or_else(|| format!("Invalid duration: {value}, expected something like 90s, 30m, 2h or 7d"))
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0.as_secs();
+        for (unit, scale) in [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60)] {
+            if secs >= scale && secs.is_multiple_of(scale) {
+                return write!(f, "{}{}", secs / scale, unit);
+            }
+        }
+        write!(f, "{secs}s")
+    }
+}
+
+impl TryFrom<String> for Interval {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Interval> for String {
+    fn from(interval: Interval) -> String {
+        interval.to_string()
+    }
+}
+
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+    let scale = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(amount.checked_mul(scale)?))
+}
+
+/// Bytes per second, written like a [`ByteSize`] with an optional `/s`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ByteRate(pub u64);
+
+impl FromStr for ByteRate {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let size = value.strip_suffix("/s").unwrap_or(value);
+        size.parse::<ByteSize>()
+            .map(|size| ByteRate(size.0))
+            .map_err(|_| format!("Invalid rate: {value}, expected something like 10MiB/s"))
+    }
+}
+
+impl fmt::Display for ByteRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/s", ByteSize(self.0))
+    }
+}
+
+impl TryFrom<String> for ByteRate {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ByteRate> for String {
+    fn from(rate: ByteRate) -> String {
+        rate.to_string()
+    }
+}
+
+/// Rounded size for people, like `3.2MiB`.
+pub fn human_bytes(bytes: u64) -> String {
+    let mut value = bytes as f64;
+    for unit in ["B", "KiB", "MiB", "GiB", "TiB"] {
+        if value < 1024.0 || unit == "TiB" {
+            return match unit {
+                "B" => format!("{bytes}B"),
+                _ => format!("{value:.1}{unit}"),
+            };
+        }
+        value /= 1024.0;
+    }
+    unreachable!()
+}
@@ -0,0 +1,460 @@
+use crate::access_log::Entry;
+use crate::capability::hex;
+use crate::http::{header, header_value};
+use crate::resolve::{self, Dir, Root};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use tiny_http::{Method, Request, Response, ResponseBox, StatusCode};
+
+const MAX_PART_HEADERS: usize = 16 * 1024;
+
+pub struct Uploads {
+    pub root: Root,
+    pub max_size: u64,
+}
+
+/// Handles PUT of a single file or a multipart/form-data POST into the
+/// directory `rel` points at below the upload directory.
+pub fn receive(uploads: &Uploads, request: &mut Request, rel: &str, entry: &mut Entry) -> ResponseBox {
+    if request
+        .body_length()
+        .is_some_and(|len| len as u64 > uploads.max_size)
+    {
+        return Response::empty(StatusCode(413)).boxed();
+    }
+
+    let result = if *request.method() == Method::Put {
+        put(uploads, request, rel, entry)
+    } else {
+        post(uploads, request, rel, entry)
+    };
+
+    match result {
+        Ok(stored) => {
+            let mut body = String::new();
+            for path in &stored {
+                match uploads.root.relative(path) {
+                    Some(name) => body.push_str(&format!("{name}\n")),
+                    None => body.push_str(&format!("{}\n", path.display())),
+                }
+            }
+            Response::from_string(body)
+                .with_status_code(StatusCode(201))
+                .with_header(header("Content-Type", "text/plain; charset=utf-8"))
+                .boxed()
+        }
+        Err(status) => Response::empty(StatusCode(status)).boxed(),
+    }
+}
+
+fn put(uploads: &Uploads, request: &mut Request, rel: &str, entry: &mut Entry) -> Result<Vec<PathBuf>, u16> {
+    let (dir, name) = match rel.rsplit_once('/') {
+        Some((dir, name)) => (dir, name),
+        None => ("", rel),
+    };
+    let name = sanitize(name).ok_or(400u16)?;
+    let dir = target_dir(uploads, dir)?;
+
+    let mut body = Capped::new(request.as_reader(), uploads.max_size);
+    let result = save(&dir, &name, |out| io::copy(&mut body, out));
+    entry.received = Some(body.read);
+    let stored = result.map_err(status_for)?;
+    entry.file = Some(stored.clone());
+    Ok(vec![stored])
+}
+
+fn post(uploads: &Uploads, request: &mut Request, rel: &str, entry: &mut Entry) -> Result<Vec<PathBuf>, u16> {
+    let boundary = header_value(request, "Content-Type")
+        .and_then(boundary)
+        .ok_or(415u16)?;
+    let dir = target_dir(uploads, rel.trim_end_matches('/'))?;
+
+    let mut body = Multipart::new(Capped::new(request.as_reader(), uploads.max_size), &boundary);
+    let mut stored = Vec::new();
+    let result = loop {
+        let part = match body.next_part() {
+            Ok(Some(part)) => part,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        let Some(name) = part.filename.as_deref().and_then(sanitize) else {
+            if let Err(e) = body.copy_part(&mut io::sink()) {
+                break Err(e);
+            }
+            continue;
+        };
+        match save(&dir, &name, |out| body.copy_part(out)) {
+            Ok(path) => stored.push(path),
+            Err(e) => break Err(e),
+        }
+    };
+    entry.received = Some(body.reader.read);
+    entry.file = stored.last().cloned();
+    result.map_err(status_for)?;
+    if stored.is_empty() {
+        return Err(400);
+    }
+    Ok(stored)
+}
+
+fn status_for(error: io::Error) -> u16 {
+    match error.kind() {
+        io::ErrorKind::FileTooLarge => 413,
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => 400,
+        _ => 500,
+    }
+}
+
+/// Opens a directory below the upload root the way downloads open files, so
+/// it cannot be swapped for a link out of it halfway through. Directories
+/// are never created on demand.
+fn target_dir(uploads: &Uploads, rel: &str) -> Result<Dir, u16> {
+    uploads.root.open_dir(rel).map_err(|e| match e.kind() {
+        _ if resolve::is_refusal(&e) => 404,
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory | io::ErrorKind::InvalidFilename => 404,
+        io::ErrorKind::PermissionDenied => 403,
+        _ => 500,
+    })
+}
+
+fn sanitize(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?;
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." || name.starts_with(".nsv-upload-") {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// Writes into a temp file next to the destination, then links it in under
+/// the first free name so concurrent uploads never clobber each other.
+fn save(
+    dir: &Dir,
+    name: &str,
+    write: impl FnOnce(&mut File) -> io::Result<u64>,
+) -> io::Result<PathBuf> {
+    let mut suffix = [0u8; 8];
+    getrandom::fill(&mut suffix)?;
+    let tmp = format!(".nsv-upload-{}.part", hex(&suffix));
+    let mut file = dir.create_new(&tmp)?;
+
+    let result = write(&mut file).and_then(|_| file.sync_all());
+    drop(file);
+    if let Err(e) = result {
+        let _ = dir.remove(&tmp);
+        return Err(e);
+    }
+
+    let stored = link_unique(dir, name, &tmp);
+    let _ = dir.remove(&tmp);
+    stored
+}
+
+fn link_unique(dir: &Dir, name: &str, tmp: &str) -> io::Result<PathBuf> {
+    for n in 0..10_000 {
+        let candidate = numbered(name, n);
+        match dir.hard_link(tmp, &candidate) {
+            Ok(()) => return Ok(dir.path.join(candidate)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            // Some filesystems can't hard link; fall back to a checked rename.
+            Err(_) if !dir.contains(&candidate) => {
+                dir.rename(tmp, &candidate)?;
+                return Ok(dir.path.join(candidate));
+            }
+            Err(_) => continue,
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, "no free file name"))
+}
+
+fn numbered(name: &str, n: u32) -> String {
+    if n == 0 {
+        return name.to_string();
+    }
+    // The first character is skipped so ".profile" does not become " (1).profile".
+    let dot = name
+        .char_indices()
+        .skip(1)
+        .find(|&(_, c)| c == '.')
+        .map(|(i, _)| i);
+    match dot {
+        Some(dot) => format!("{} ({n}){}", &name[..dot], &name[dot..]),
+        None => format!("{name} ({n})"),
+    }
+}
+
+fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        .filter(|value| !value.is_empty() && value.len() <= 70)
+}
+
+/// Fails with `FileTooLarge` once more than `limit` bytes were read.
+struct Capped<R> {
+    inner: R,
+    limit: u64,
+    read: u64,
+}
+
+impl<R> Capped<R> {
+    fn new(inner: R, limit: u64) -> Self {
+        Capped {
+            inner,
+            limit,
+            read: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Capped<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.read > self.limit {
+            return Err(io::Error::new(io::ErrorKind::FileTooLarge, "upload too large"));
+        }
+        Ok(n)
+    }
+}
+
+struct PartHeaders {
+    filename: Option<String>,
+}
+
+/// Streaming multipart/form-data splitter.
+struct Multipart<R> {
+    reader: R,
+    buf: Vec<u8>,
+    delimiter: Vec<u8>,
+    started: bool,
+    done: bool,
+}
+
+impl<R: Read> Multipart<R> {
+    fn new(reader: R, boundary: &str) -> Self {
+        Multipart {
+            reader,
+            // Pretend the body starts with a line break so the first
+            // delimiter looks like every other one.
+            buf: b"\r\n".to_vec(),
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            started: false,
+            done: false,
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0u8; 64 * 1024];
+        let n = self.reader.read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+
+    fn fill_to(&mut self, len: usize) -> io::Result<()> {
+        while self.buf.len() < len {
+            if self.fill()? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn next_part(&mut self) -> io::Result<Option<PartHeaders>> {
+        if !self.started {
+            self.started = true;
+            self.copy_part(&mut io::sink())?;
+        }
+        if self.done {
+            return Ok(None);
+        }
+
+        let end = loop {
+            if let Some(end) = find(&self.buf, b"\r\n\r\n") {
+                break end;
+            }
+            if self.buf.len() > MAX_PART_HEADERS {
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+            if self.fill()? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        };
+        let head = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+        self.buf.drain(..end + 4);
+
+        let filename = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-disposition"))
+            .and_then(|(_, value)| disposition_filename(value));
+        Ok(Some(PartHeaders { filename }))
+    }
+
+    /// Streams the current part into `out` and consumes the delimiter after it.
+    fn copy_part(&mut self, out: &mut impl Write) -> io::Result<u64> {
+        let mut written = 0u64;
+        loop {
+            if let Some(pos) = find(&self.buf, &self.delimiter) {
+                out.write_all(&self.buf[..pos])?;
+                written += pos as u64;
+                self.buf.drain(..pos + self.delimiter.len());
+                self.fill_to(2)?;
+                if self.buf.starts_with(b"--") {
+                    self.done = true;
+                } else {
+                    // Skip transport padding up to the line break.
+                    loop {
+                        if let Some(eol) = find(&self.buf, b"\r\n") {
+                            self.buf.drain(..eol + 2);
+                            break;
+                        }
+                        if self.buf.len() > 1024 || self.fill()? == 0 {
+                            return Err(io::ErrorKind::InvalidData.into());
+                        }
+                    }
+                }
+                return Ok(written);
+            }
+
+            let keep = (self.delimiter.len() - 1).min(self.buf.len());
+            let flush = self.buf.len() - keep;
+            out.write_all(&self.buf[..flush])?;
+            written += flush as u64;
+            self.buf.drain(..flush);
+            if self.fill()? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn disposition_filename(value: &str) -> Option<String> {
+    value
+        .split(';')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("filename"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbered_inserts_before_the_extension() {
+        assert_eq!(numbered("report.pdf", 0), "report.pdf");
+        assert_eq!(numbered("report.pdf", 1), "report (1).pdf");
+        assert_eq!(numbered("a.tar.gz", 2), "a (2).tar.gz");
+        assert_eq!(numbered("README", 3), "README (3)");
+        assert_eq!(numbered(".profile", 1), ".profile (1)");
+    }
+
+    #[test]
+    fn numbered_handles_multibyte_names() {
+        assert_eq!(numbered("é.txt", 1), "é (1).txt");
+        assert_eq!(numbered("日本語", 1), "日本語 (1)");
+        assert_eq!(numbered("é", 1), "é (1)");
+    }
+
+    #[test]
+    fn sanitize_keeps_only_the_last_component() {
+        assert_eq!(sanitize("report.pdf").as_deref(), Some("report.pdf"));
+        assert_eq!(sanitize("../../etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(sanitize("C:\\Users\\me\\a.txt").as_deref(), Some("a.txt"));
+        assert_eq!(sanitize(" a\u{7}b.txt ").as_deref(), Some("ab.txt"));
+    }
+
+    #[test]
+    fn sanitize_rejects_empty_and_reserved_names() {
+        for name in ["", "   ", ".", "..", "dir/", ".nsv-upload-1234.part"] {
+            assert_eq!(sanitize(name), None, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn boundary_from_content_type() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=abc").as_deref(),
+            Some("abc")
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data; charset=utf-8; Boundary=\"a b\"").as_deref(),
+            Some("a b")
+        );
+        assert_eq!(boundary("multipart/mixed; boundary=abc"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(boundary("multipart/form-data; boundary="), None);
+        assert_eq!(boundary(&format!("multipart/form-data; boundary={}", "x".repeat(71))), None);
+    }
+
+    fn parts(body: &[u8], boundary: &str) -> io::Result<Vec<(Option<String>, Vec<u8>)>> {
+        let mut multipart = Multipart::new(body, boundary);
+        let mut parts = Vec::new();
+        while let Some(part) = multipart.next_part()? {
+            let mut data = Vec::new();
+            multipart.copy_part(&mut data)?;
+            parts.push((part.filename, data));
+        }
+        Ok(parts)
+    }
+
+    #[test]
+    fn multipart_splits_parts() {
+        let body = b"preamble\r\n--XX\r\n\
+Content-Disposition: form-data; name=\"a\"; filename=\"a.txt\"\r\n\
+Content-Type: text/plain\r\n\r\n\
+hello\r\n--XX  \r\n\
+Content-Disposition: form-data; name=\"note\"\r\n\r\n\
+not a file\r\n--XX\r\n\
+content-disposition: form-data; name=\"b\"; filename=\"b.bin\"\r\n\r\n\
+\r\n--X\r\n\r\n--XX--\r\nepilogue";
+        let parts = parts(body, "XX").unwrap();
+        assert_eq!(
+            parts,
+            vec![
+                (Some("a.txt".to_string()), b"hello".to_vec()),
+                (None, b"not a file".to_vec()),
+                (Some("b.bin".to_string()), b"\r\n--X\r\n".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn multipart_streams_parts_larger_than_a_read() {
+        let data = vec![b'x'; 200 * 1024];
+        let mut body = b"--B\r\nContent-Disposition: form-data; filename=\"big\"\r\n\r\n".to_vec();
+        body.extend_from_slice(&data);
+        body.extend_from_slice(b"\r\n--B--\r\n");
+        let parts = parts(&body, "B").unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].1, data);
+    }
+
+    #[test]
+    fn multipart_rejects_truncated_bodies() {
+        let body = b"--B\r\nContent-Disposition: form-data; filename=\"a\"\r\n\r\nhalf";
+        let error = parts(body, "B").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn capped_fails_past_the_limit() {
+        let mut capped = Capped::new(&b"12345"[..], 4);
+        let error = io::copy(&mut capped, &mut io::sink()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+        let mut capped = Capped::new(&b"1234"[..], 4);
+        assert_eq!(io::copy(&mut capped, &mut io::sink()).unwrap(), 4);
+    }
+}